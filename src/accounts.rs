@@ -0,0 +1,822 @@
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::{
+    config::{AccountBackend, AccountsConfig, ChainPolicy, TokenTransport},
+    persist::JsonFile,
+    tokens, unix_now,
+};
+
+mod ldap;
+mod sql;
+
+pub use ldap::LdapAccounts;
+pub use sql::SqlAccounts;
+
+#[allow(clippy::upper_case_acronyms)]
+pub enum AccountServerKind {
+    API(ApiAccounts),
+    File(FileAccounts),
+    Ldap(LdapAccounts),
+    Sql(SqlAccounts),
+    Chain(Vec<ChainLink>),
+}
+
+/// A backend in a chain, with what to do when it can't answer.
+pub struct ChainLink {
+    backend: AccountServerKind,
+    on_miss: ChainPolicy,
+    on_error: ChainPolicy,
+}
+
+impl AccountServerKind {
+    pub async fn load(config: &AccountsConfig) -> Result<Self, String> {
+        match config.backend {
+            AccountBackend::Api => Ok(AccountServerKind::API(ApiAccounts::new(config)?)),
+            AccountBackend::File => Ok(AccountServerKind::File(FileAccounts::open(config)?)),
+            AccountBackend::Ldap => Ok(AccountServerKind::Ldap(LdapAccounts::new(&config.ldap)?)),
+            AccountBackend::Sql => Ok(AccountServerKind::Sql(SqlAccounts::connect(&config.sql).await?)),
+            AccountBackend::Chain => {
+                let mut links = Vec::with_capacity(config.chain.len());
+                for link in &config.chain {
+                    let mut link = link.clone();
+                    if link.pepper.is_none() {
+                        link.pepper.clone_from(&config.pepper);
+                    }
+                    links.push(ChainLink {
+                        backend: Box::pin(AccountServerKind::load(&link)).await?,
+                        on_miss: link.on_miss,
+                        on_error: link.on_error,
+                    });
+                }
+                Ok(AccountServerKind::Chain(links))
+            }
+        }
+    }
+
+    /// Checks what `load` would read, without connecting to anything or
+    /// creating tables, for `--check-config`.
+    pub fn check(config: &AccountsConfig) -> Result<(), String> {
+        match config.backend {
+            AccountBackend::Api => config.api_secret().map(drop),
+            AccountBackend::File => FileAccounts::read(&config.file).map(drop),
+            AccountBackend::Ldap => config.ldap.bind_password().map(drop),
+            AccountBackend::Sql => Ok(()),
+            AccountBackend::Chain => config.chain.iter().try_for_each(AccountServerKind::check),
+        }
+    }
+
+    pub async fn get(&self, k: &str) -> Option<Account> {
+        self.lookup(k).await.unwrap_or_else(|e| {
+            warn!("account lookup failed: {e}");
+            None
+        })
+    }
+
+    /// Like `get`, but tells a failed lookup apart from an unknown token.
+    pub async fn lookup(&self, k: &str) -> Result<Option<Account>, AccountError> {
+        match self {
+            AccountServerKind::API(accounts) => {
+                accounts.get(k).await
+            }
+            AccountServerKind::File(accounts) => {
+                Ok(accounts.get(k).await)
+            }
+            AccountServerKind::Ldap(accounts) => {
+                accounts.get(k).await
+            }
+            AccountServerKind::Sql(accounts) => {
+                accounts.get(k).await
+            }
+            AccountServerKind::Chain(links) => {
+                let mut last_error = None;
+                for link in links {
+                    match Box::pin(link.backend.lookup(k)).await {
+                        Ok(Some(account)) => return Ok(Some(account)),
+                        Ok(None) if link.on_miss == ChainPolicy::Stop => return Ok(None),
+                        Ok(None) => {}
+                        Err(e) if link.on_error == ChainPolicy::Stop => return Err(e),
+                        Err(e) => {
+                            warn!("account backend failed, trying the next one: {e}");
+                            last_error = Some(e);
+                        }
+                    }
+                }
+                // Only a miss if no backend failed along the way.
+                match last_error {
+                    Some(e) => Err(e),
+                    None => Ok(None),
+                }
+            }
+        }
+    }
+
+    /// The file backend accounts can be managed through, the first one in
+    /// a chain.
+    pub fn file_accounts(&self) -> Option<&FileAccounts> {
+        match self {
+            AccountServerKind::API(_) | AccountServerKind::Ldap(_) | AccountServerKind::Sql(_) => None,
+            AccountServerKind::File(accounts) => Some(accounts),
+            AccountServerKind::Chain(links) => links.iter().find_map(|link| link.backend.file_accounts()),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AccountError {
+    #[error("account service request failed: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("account service returned {0}")]
+    Status(StatusCode),
+    #[error("ldap lookup failed: {0}")]
+    Ldap(#[from] ::ldap3::LdapError),
+    #[error("sql lookup failed: {0}")]
+    Sql(#[from] sqlx::Error),
+}
+
+/// What a token grants: the profiles it may join as, and whether it is
+/// currently usable.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(from = "AccountRepr", into = "AccountRepr")]
+pub struct Account {
+    pub profiles: Vec<String>,
+    pub enabled: bool,
+    /// Unix timestamp after which the token stops working.
+    pub expires_at: Option<u64>,
+    pub notes: Option<String>,
+}
+
+/// On-disk and on-the-wire form of an `Account`: either a bare UUID, as in
+/// the original `accounts.json` and `{"uuid": ...}` API answers, or a record.
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum AccountRepr {
+    Uuid(String),
+    Record(AccountRecord),
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct AccountRecord {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub profiles: Vec<String>,
+    #[serde(default = "enabled_default")]
+    pub enabled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+}
+
+fn enabled_default() -> bool {
+    true
+}
+
+impl From<AccountRecord> for Account {
+    fn from(record: AccountRecord) -> Self {
+        let mut profiles = record.profiles;
+        if let Some(uuid) = record.uuid
+            && !profiles.contains(&uuid)
+        {
+            profiles.insert(0, uuid);
+        }
+        Account { profiles, enabled: record.enabled, expires_at: record.expires_at, notes: record.notes }
+    }
+}
+
+impl From<AccountRepr> for Account {
+    fn from(repr: AccountRepr) -> Self {
+        match repr {
+            AccountRepr::Uuid(uuid) => Account::single(uuid),
+            AccountRepr::Record(record) => record.into(),
+        }
+    }
+}
+
+impl From<Account> for AccountRepr {
+    fn from(account: Account) -> Self {
+        // Keep plain accounts in the original format.
+        if account.profiles.len() == 1 && account.enabled && account.expires_at.is_none() && account.notes.is_none() {
+            return AccountRepr::Uuid(account.profiles.into_iter().next().unwrap());
+        }
+        AccountRepr::Record(AccountRecord {
+            uuid: None,
+            profiles: account.profiles,
+            enabled: account.enabled,
+            expires_at: account.expires_at,
+            notes: account.notes,
+        })
+    }
+}
+
+impl Account {
+    pub fn single(uuid: String) -> Self {
+        Account { profiles: vec![uuid], enabled: true, expires_at: None, notes: None }
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|t| now >= t)
+    }
+
+    /// Whether `profile` is one of the allowed UUIDs, ignoring dashes and case.
+    pub fn allows_profile(&self, profile: &str) -> bool {
+        let profile = normalize_uuid(profile);
+        self.profiles.iter().any(|p| normalize_uuid(p) == profile)
+    }
+}
+
+impl fmt::Display for Account {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.profiles.join(", "))?;
+        if !self.enabled {
+            write!(f, " (disabled)")?;
+        }
+        Ok(())
+    }
+}
+
+pub fn normalize_uuid(uuid: &str) -> String {
+    uuid.replace('-', "").to_lowercase()
+}
+
+/// Like `normalize_uuid`, but `None` unless the result is 32 hex digits.
+pub fn parse_uuid(uuid: &str) -> Option<String> {
+    let uuid = normalize_uuid(uuid);
+    (uuid.len() == 32 && uuid.chars().all(|c| c.is_ascii_hexdigit())).then_some(uuid)
+}
+
+/// How to reach the account service, shared with background refreshes.
+struct ApiLookup {
+    client: Client,
+    endpoint: String,
+    secret: Option<String>,
+    /// Sign lookups with `secret` instead of sending it as a bearer token.
+    sign: bool,
+    transport: TokenTransport,
+    token_header: String,
+}
+
+impl ApiLookup {
+    /// `Ok(None)` when the service doesn't know the token (a 404 answer),
+    /// `Err` when it couldn't be asked or refused to answer, e.g. because of
+    /// a wrong secret or rate limiting.
+    async fn fetch(&self, token: &str) -> Result<Option<Account>, AccountError> {
+        let mut req = match self.transport {
+            TokenTransport::Query => self.client.get(&self.endpoint).query(&[("token", token)]),
+            TokenTransport::Body => self.client.post(&self.endpoint).json(&json!({ "token": token })),
+            TokenTransport::Header => self.client.get(&self.endpoint).header(&self.token_header, token),
+        };
+        match &self.secret {
+            Some(s) if self.sign => {
+                let timestamp = unix_now().to_string();
+                req = req
+                    .header("X-Ygg-Timestamp", &timestamp)
+                    .header("X-Ygg-Signature", tokens::sign(s, &timestamp, token));
+            }
+            Some(s) => req = req.bearer_auth(s),
+            None => {}
+        }
+        let resp = req.send().await?;
+        let status = resp.status();
+        if status == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !status.is_success() {
+            return Err(AccountError::Status(status));
+        }
+        Ok(Some(resp.json::<Account>().await?))
+    }
+}
+
+struct CacheEntry {
+    account: Option<Account>,
+    fetched_at: Instant,
+    refreshing: bool,
+}
+
+type LookupCache = Arc<Mutex<HashMap<String, CacheEntry>>>;
+
+// Past this many entries, expired ones are dropped on insert.
+const CACHE_PRUNE_THRESHOLD: usize = 10_000;
+
+/// Looks tokens up at an external HTTP endpoint, caching answers. Known
+/// tokens are kept for `ttl` and unknown ones for `negative_ttl`; a known
+/// token is then served for up to `stale_ttl` longer while it is refreshed
+/// in the background, or while the service is unreachable.
+pub struct ApiAccounts {
+    lookup: Arc<ApiLookup>,
+    cache: LookupCache,
+    ttl: Duration,
+    negative_ttl: Duration,
+    stale_ttl: Duration,
+}
+
+impl ApiAccounts {
+    pub fn new(config: &AccountsConfig) -> Result<Self, String> {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .map_err(|e| format!("failed to build account service client: {e}"))?;
+        Ok(ApiAccounts {
+            lookup: Arc::new(ApiLookup {
+                client,
+                endpoint: config.endpoint.clone().unwrap(),
+                secret: config.api_secret()?,
+                sign: config.sign_requests,
+                transport: config.transport,
+                token_header: config.token_header.clone(),
+            }),
+            cache: Arc::default(),
+            ttl: Duration::from_secs(config.cache.ttl),
+            negative_ttl: Duration::from_secs(config.cache.negative_ttl),
+            stale_ttl: Duration::from_secs(config.cache.stale_ttl),
+        })
+    }
+
+    pub async fn get(&self, token: &str) -> Result<Option<Account>, AccountError> {
+        if let Some(entry) = self.cache.lock().await.get_mut(token) {
+            let age = entry.fetched_at.elapsed();
+            let ttl = if entry.account.is_some() { self.ttl } else { self.negative_ttl };
+            if age < ttl {
+                return Ok(entry.account.clone());
+            }
+            if entry.account.is_some() && age < self.ttl + self.stale_ttl {
+                if !entry.refreshing {
+                    entry.refreshing = true;
+                    self.spawn_refresh(token.to_string());
+                }
+                return Ok(entry.account.clone());
+            }
+        }
+
+        let account = self.lookup.fetch(token).await?;
+        Self::store(&self.cache, self.max_age(), token, account.clone()).await;
+        Ok(account)
+    }
+
+    /// Age past which an entry can't be served any more.
+    fn max_age(&self) -> Duration {
+        self.negative_ttl.max(self.ttl + self.stale_ttl)
+    }
+
+    fn spawn_refresh(&self, token: String) {
+        let lookup = self.lookup.clone();
+        let cache = self.cache.clone();
+        let max_age = self.max_age();
+        tokio::spawn(async move {
+            match lookup.fetch(&token).await {
+                Ok(account) => Self::store(&cache, max_age, &token, account).await,
+                Err(e) => {
+                    warn!("serving cached account while the account service fails: {e}");
+                    if let Some(entry) = cache.lock().await.get_mut(&token) {
+                        entry.refreshing = false;
+                    }
+                }
+            }
+        });
+    }
+
+    async fn store(cache: &LookupCache, max_age: Duration, token: &str, account: Option<Account>) {
+        let mut cache = cache.lock().await;
+        if cache.len() >= CACHE_PRUNE_THRESHOLD {
+            cache.retain(|_, entry| entry.fetched_at.elapsed() < max_age);
+        }
+        cache.insert(token.to_string(), CacheEntry { account, fetched_at: Instant::now(), refreshing: false });
+    }
+}
+
+/// Token -> account map backed by `accounts.json`, editable at runtime. Keys
+/// are either plaintext tokens or hashes from the `tokens` module.
+pub struct FileAccounts {
+    pepper: String,
+    hash_tokens: bool,
+    tokens: JsonFile<HashMap<String, Account>>,
+}
+
+impl FileAccounts {
+    pub fn open(config: &AccountsConfig) -> Result<Self, String> {
+        let tokens = Self::read(&config.file)?;
+        Ok(FileAccounts {
+            pepper: config.pepper.clone().unwrap_or_default(),
+            hash_tokens: config.hash_tokens,
+            tokens: JsonFile::new(config.file.clone(), tokens),
+        })
+    }
+
+    pub fn read(path: &Path) -> Result<HashMap<String, Account>, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+        serde_json::from_str(&contents)
+            .map_err(|e| format!("failed to parse {}: {e}", path.display()))
+    }
+
+    pub fn path(&self) -> &Path {
+        self.tokens.path()
+    }
+
+    /// Re-reads the file and swaps the map in if it parses, keeping the
+    /// current one otherwise. Logs which tokens changed.
+    pub async fn reload(&self) -> Result<(), String> {
+        let old = self.tokens.reload(Self::read).await?;
+        let new = self.tokens.read().await;
+
+        let mut added = 0;
+        let mut changed = 0;
+        for (token, account) in new.iter() {
+            match old.get(token) {
+                None => {
+                    added += 1;
+                    info!("accounts reload: token added for {account}");
+                }
+                Some(old) if old != account => {
+                    changed += 1;
+                    info!("accounts reload: token changed from {old} to {account}");
+                }
+                Some(_) => {}
+            }
+        }
+        let mut removed = 0;
+        for (token, account) in &old {
+            if !new.contains_key(token) {
+                removed += 1;
+                info!("accounts reload: token removed for {account}");
+            }
+        }
+        if added + changed + removed > 0 {
+            info!(
+                "reloaded {}: {added} added, {changed} changed, {removed} removed",
+                self.path().display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Finds the key `token` is stored under, plaintext or hashed.
+    fn find<'a>(&self, tokens: &'a HashMap<String, Account>, token: &str) -> Option<&'a String> {
+        if !tokens::is_hashed(token)
+            && let Some((key, _)) = tokens.get_key_value(token)
+        {
+            return Some(key);
+        }
+        tokens
+            .keys()
+            .filter(|key| tokens::is_hashed(key))
+            .find(|key| tokens::verify(key, token, &self.pepper))
+    }
+
+    pub async fn get(&self, token: &str) -> Option<Account> {
+        let tokens = self.tokens.read().await;
+        self.find(&tokens, token).map(|key| tokens[key].clone())
+    }
+
+    pub async fn list(&self) -> HashMap<String, Account> {
+        self.tokens.read().await.clone()
+    }
+
+    /// Maps `token` to `account` and writes the file. Returns the previous
+    /// account. New tokens are stored hashed when `hash_tokens` is set.
+    pub async fn insert(&self, token: &str, account: Account) -> io::Result<Option<Account>> {
+        let previous = self
+            .tokens
+            .update(|tokens| {
+                let key = match self.find(tokens, token) {
+                    Some(key) => key.clone(),
+                    None if self.hash_tokens => tokens::hash(token, &self.pepper),
+                    None => token.to_string(),
+                };
+                Some(tokens.insert(key, account))
+            })
+            .await?;
+        Ok(previous.flatten())
+    }
+
+    /// Removes `token`, given either as the token itself or as the key it is
+    /// stored under, and writes the file. Returns the account it mapped to.
+    pub async fn remove(&self, token: &str) -> io::Result<Option<Account>> {
+        self.tokens
+            .update(|tokens| {
+                let key = if tokens.contains_key(token) {
+                    token.to_string()
+                } else {
+                    self.find(tokens, token)?.clone()
+                };
+                tokens.remove(&key)
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use ChainPolicy::{Continue, Stop};
+
+    const UUID: &str = "0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn bare_uuid_round_trips() {
+        let json = format!("\"{UUID}\"");
+        let account: Account = serde_json::from_str(&json).unwrap();
+        assert_eq!(account, Account::single(UUID.to_string()));
+        assert_eq!(serde_json::to_string(&account).unwrap(), json);
+    }
+
+    #[test]
+    fn uuid_object_reads_as_single_profile() {
+        let account: Account = serde_json::from_str(&format!("{{\"uuid\": \"{UUID}\"}}")).unwrap();
+        assert_eq!(account, Account::single(UUID.to_string()));
+    }
+
+    #[test]
+    fn record_round_trips() {
+        let account = Account {
+            profiles: vec![UUID.to_string(), "fedcba9876543210fedcba9876543210".to_string()],
+            enabled: false,
+            expires_at: Some(1767225600),
+            notes: Some("test".to_string()),
+        };
+        let json = serde_json::to_string(&account).unwrap();
+        assert_eq!(serde_json::from_str::<Account>(&json).unwrap(), account);
+    }
+
+    #[test]
+    fn original_accounts_file_parses() {
+        let file = format!("{{\"token\": \"{UUID}\"}}");
+        let accounts: HashMap<String, Account> = serde_json::from_str(&file).unwrap();
+        assert_eq!(accounts["token"], Account::single(UUID.to_string()));
+        assert_eq!(serde_json::to_string(&accounts).unwrap(), file.replace(' ', ""));
+    }
+
+    fn file(tokens: &[(&str, &str)]) -> AccountServerKind {
+        let tokens = tokens.iter().map(|(token, uuid)| (token.to_string(), Account::single(uuid.to_string())));
+        AccountServerKind::File(FileAccounts {
+            pepper: String::new(),
+            hash_tokens: false,
+            tokens: JsonFile::new(PathBuf::new(), tokens.collect()),
+        })
+    }
+
+    /// An API backend nothing listens for, so every lookup fails.
+    fn failing() -> AccountServerKind {
+        let config = AccountsConfig { endpoint: Some("http://127.0.0.1:1/".to_string()), ..Default::default() };
+        AccountServerKind::API(ApiAccounts::new(&config).unwrap())
+    }
+
+    fn link(backend: AccountServerKind, on_miss: ChainPolicy, on_error: ChainPolicy) -> ChainLink {
+        ChainLink { backend, on_miss, on_error }
+    }
+
+    #[tokio::test]
+    async fn chain_answers_from_first_backend_knowing_the_token() {
+        let chain = AccountServerKind::Chain(vec![
+            link(file(&[("a", UUID)]), Continue, Continue),
+            link(file(&[("a", "other"), ("b", UUID)]), Continue, Continue),
+        ]);
+        assert_eq!(chain.lookup("a").await.unwrap(), Some(Account::single(UUID.to_string())));
+        assert_eq!(chain.lookup("b").await.unwrap(), Some(Account::single(UUID.to_string())));
+        assert_eq!(chain.lookup("c").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn chain_stops_on_miss() {
+        let chain = AccountServerKind::Chain(vec![
+            link(file(&[]), Stop, Continue),
+            link(file(&[("a", UUID)]), Continue, Continue),
+        ]);
+        assert_eq!(chain.lookup("a").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn chain_continues_past_errors() {
+        let chain = AccountServerKind::Chain(vec![
+            link(failing(), Continue, Continue),
+            link(file(&[("a", UUID)]), Continue, Continue),
+        ]);
+        assert_eq!(chain.lookup("a").await.unwrap(), Some(Account::single(UUID.to_string())));
+        // A miss after a failure is still a failure, the token may be known
+        // to the backend that couldn't answer.
+        assert!(chain.lookup("b").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn chain_stops_on_error() {
+        let chain = AccountServerKind::Chain(vec![
+            link(failing(), Continue, Stop),
+            link(file(&[("a", UUID)]), Continue, Continue),
+        ]);
+        assert!(chain.lookup("a").await.is_err());
+        assert_eq!(chain.get("a").await, None);
+    }
+
+    #[test]
+    fn parse_uuid_accepts_dashed_and_rejects_others() {
+        assert_eq!(parse_uuid("01234567-89AB-cdef-0123-456789abcdef").as_deref(), Some(UUID));
+        assert_eq!(parse_uuid("../blockedservers"), None);
+        assert_eq!(parse_uuid(&format!("{UUID}?x")), None);
+    }
+
+    /// A lookup as the mock account service received it.
+    #[derive(Clone, Debug, Default)]
+    struct Seen {
+        method: String,
+        query: Option<String>,
+        body: String,
+        token_header: Option<String>,
+    }
+
+    /// Account service answering every lookup with `status`, and with
+    /// `UUID` when that is 200, after `delay`.
+    struct Service {
+        status: std::sync::Mutex<StatusCode>,
+        delay: std::sync::Mutex<Duration>,
+        hits: std::sync::atomic::AtomicUsize,
+        last: std::sync::Mutex<Seen>,
+    }
+
+    impl Service {
+        async fn start(status: StatusCode) -> (Arc<Service>, String) {
+            use axum::{
+                extract::State,
+                http::{HeaderMap, Method, Uri},
+                response::IntoResponse,
+            };
+
+            async fn answer(
+                State(service): State<Arc<Service>>,
+                method: Method,
+                uri: Uri,
+                headers: HeaderMap,
+                body: String,
+            ) -> axum::response::Response {
+                service.hits.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                *service.last.lock().unwrap() = Seen {
+                    method: method.to_string(),
+                    query: uri.query().map(str::to_string),
+                    body,
+                    token_header: headers.get("X-Ygg-Token").and_then(|v| v.to_str().ok()).map(str::to_string),
+                };
+                let delay = *service.delay.lock().unwrap();
+                tokio::time::sleep(delay).await;
+                let status = *service.status.lock().unwrap();
+                if status == StatusCode::OK {
+                    axum::Json(json!({ "uuid": UUID })).into_response()
+                } else {
+                    status.into_response()
+                }
+            }
+
+            let service = Arc::new(Service {
+                status: std::sync::Mutex::new(status),
+                delay: Default::default(),
+                hits: Default::default(),
+                last: Default::default(),
+            });
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let endpoint = format!("http://{}/lookup", listener.local_addr().unwrap());
+            let app = axum::Router::new().route("/lookup", axum::routing::any(answer)).with_state(service.clone());
+            tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+            (service, endpoint)
+        }
+
+        fn hits(&self) -> usize {
+            self.hits.load(std::sync::atomic::Ordering::SeqCst)
+        }
+
+        fn set_status(&self, status: StatusCode) {
+            *self.status.lock().unwrap() = status;
+        }
+
+        fn last(&self) -> Seen {
+            self.last.lock().unwrap().clone()
+        }
+    }
+
+    fn api(endpoint: &str, transport: TokenTransport) -> ApiAccounts {
+        let config = AccountsConfig { endpoint: Some(endpoint.to_string()), transport, ..Default::default() };
+        ApiAccounts::new(&config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn query_transport_sends_token_in_url() {
+        let (service, endpoint) = Service::start(StatusCode::OK).await;
+        let account = api(&endpoint, TokenTransport::Query).get("a b&c").await.unwrap();
+        assert_eq!(account, Some(Account::single(UUID.to_string())));
+        let seen = service.last();
+        assert_eq!(seen.method, "GET");
+        assert_eq!(seen.query.as_deref(), Some("token=a+b%26c"));
+        assert_eq!(seen.token_header, None);
+    }
+
+    #[tokio::test]
+    async fn body_transport_posts_token() {
+        let (service, endpoint) = Service::start(StatusCode::OK).await;
+        api(&endpoint, TokenTransport::Body).get("secret").await.unwrap();
+        let seen = service.last();
+        assert_eq!(seen.method, "POST");
+        assert_eq!(seen.query, None);
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&seen.body).unwrap(), json!({ "token": "secret" }));
+    }
+
+    #[tokio::test]
+    async fn header_transport_sends_token_header() {
+        let (service, endpoint) = Service::start(StatusCode::OK).await;
+        api(&endpoint, TokenTransport::Header).get("secret").await.unwrap();
+        let seen = service.last();
+        assert_eq!(seen.method, "GET");
+        assert_eq!(seen.query, None);
+        assert_eq!(seen.token_header.as_deref(), Some("secret"));
+    }
+
+    /// An API backend with lifetimes short enough to wait out in a test.
+    fn cached_api(endpoint: &str, ttl: u64, negative_ttl: u64, stale_ttl: u64) -> ApiAccounts {
+        let mut api = api(endpoint, TokenTransport::Query);
+        api.ttl = Duration::from_millis(ttl);
+        api.negative_ttl = Duration::from_millis(negative_ttl);
+        api.stale_ttl = Duration::from_millis(stale_ttl);
+        api
+    }
+
+    #[tokio::test]
+    async fn fresh_entries_are_served_from_cache() {
+        let (service, endpoint) = Service::start(StatusCode::OK).await;
+        let api = cached_api(&endpoint, 60_000, 60_000, 0);
+        for _ in 0..3 {
+            assert_eq!(api.get("a").await.unwrap(), Some(Account::single(UUID.to_string())));
+        }
+        assert_eq!(service.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn stale_entries_are_served_while_refreshed_once() {
+        let (service, endpoint) = Service::start(StatusCode::OK).await;
+        let api = cached_api(&endpoint, 50, 0, 60_000);
+        api.get("a").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // Keep the refresh in flight while the stale entry is asked for again.
+        *service.delay.lock().unwrap() = Duration::from_millis(200);
+        let started = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(api.get("a").await.unwrap(), Some(Account::single(UUID.to_string())));
+        }
+        assert!(started.elapsed() < Duration::from_millis(200));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(service.hits(), 2);
+
+        // The refresh made the entry fresh again.
+        api.get("a").await.unwrap();
+        assert_eq!(service.hits(), 2);
+    }
+
+    #[tokio::test]
+    async fn stale_entries_are_served_while_the_service_fails() {
+        let (service, endpoint) = Service::start(StatusCode::OK).await;
+        let api = cached_api(&endpoint, 50, 0, 60_000);
+        api.get("a").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        service.set_status(StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(api.get("a").await.unwrap(), Some(Account::single(UUID.to_string())));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // The failed refresh is retried on the next lookup.
+        assert_eq!(api.get("a").await.unwrap(), Some(Account::single(UUID.to_string())));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(service.hits(), 3);
+    }
+
+    #[tokio::test]
+    async fn unknown_tokens_expire_after_negative_ttl() {
+        let (service, endpoint) = Service::start(StatusCode::NOT_FOUND).await;
+        let api = cached_api(&endpoint, 60_000, 50, 60_000);
+        assert_eq!(api.get("a").await.unwrap(), None);
+        assert_eq!(api.get("a").await.unwrap(), None);
+        assert_eq!(service.hits(), 1);
+
+        // Not served stale: a token created meanwhile is picked up right away.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        service.set_status(StatusCode::OK);
+        assert_eq!(api.get("a").await.unwrap(), Some(Account::single(UUID.to_string())));
+        assert_eq!(service.hits(), 2);
+    }
+
+    #[tokio::test]
+    async fn failures_are_not_cached() {
+        let (service, endpoint) = Service::start(StatusCode::UNAUTHORIZED).await;
+        let api = cached_api(&endpoint, 60_000, 60_000, 60_000);
+        assert!(api.get("a").await.is_err());
+        assert!(api.get("a").await.is_err());
+        assert_eq!(service.hits(), 2);
+    }
+}
@@ -0,0 +1,88 @@
+use ldap3::{Ldap, LdapConnAsync, LdapConnSettings, LdapError, Scope, SearchEntry, ldap_escape};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use super::{Account, AccountError};
+use crate::config::LdapConfig;
+
+/// Resolves tokens stored in a directory attribute to the Minecraft UUIDs
+/// of the same entry, binding as a service account.
+pub struct LdapAccounts {
+    url: String,
+    bind_dn: String,
+    bind_password: String,
+    base_dn: String,
+    filter: String,
+    token_attribute: String,
+    uuid_attribute: String,
+    connect_timeout: Duration,
+    timeout: Duration,
+    // Bound connection shared by lookups, reopened when it fails.
+    conn: Mutex<Option<Ldap>>,
+}
+
+impl LdapAccounts {
+    pub fn new(config: &LdapConfig) -> Result<Self, String> {
+        Ok(LdapAccounts {
+            url: config.url.clone(),
+            bind_dn: config.bind_dn.clone(),
+            bind_password: config.bind_password()?,
+            base_dn: config.base_dn.clone(),
+            filter: config.filter.clone(),
+            token_attribute: config.token_attribute.clone(),
+            uuid_attribute: config.uuid_attribute.clone(),
+            connect_timeout: Duration::from_millis(config.connect_timeout_ms),
+            timeout: Duration::from_millis(config.timeout_ms),
+            conn: Mutex::new(None),
+        })
+    }
+
+    async fn connect(&self) -> Result<Ldap, LdapError> {
+        let settings = LdapConnSettings::new().set_conn_timeout(self.connect_timeout);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.url).await?;
+        ldap3::drive!(conn);
+        ldap.with_timeout(self.timeout)
+            .simple_bind(&self.bind_dn, &self.bind_password)
+            .await?
+            .success()?;
+        Ok(ldap)
+    }
+
+    pub async fn get(&self, token: &str) -> Result<Option<Account>, AccountError> {
+        let cached = self.conn.lock().await.clone();
+        if let Some(mut ldap) = cached {
+            match self.search(&mut ldap, token).await {
+                Ok(account) => return Ok(account),
+                // The connection may have timed out, retry once on a fresh one.
+                Err(e) => warn!("ldap lookup failed, reconnecting: {e}"),
+            }
+        }
+        let mut ldap = self.connect().await?;
+        let account = self.search(&mut ldap, token).await?;
+        *self.conn.lock().await = Some(ldap);
+        Ok(account)
+    }
+
+    async fn search(&self, ldap: &mut Ldap, token: &str) -> Result<Option<Account>, LdapError> {
+        let filter = format!("(&{}({}={}))", self.filter, self.token_attribute, ldap_escape(token));
+        let (entries, _) = ldap
+            .with_timeout(self.timeout)
+            .search(&self.base_dn, Scope::Subtree, &filter, vec![self.uuid_attribute.as_str()])
+            .await?
+            .success()?;
+        if entries.len() > 1 {
+            warn!("{} ldap entries share a token, using the first", entries.len());
+        }
+        let Some(entry) = entries.into_iter().next() else {
+            return Ok(None);
+        };
+        let mut entry = SearchEntry::construct(entry);
+        let profiles = entry.attrs.remove(&self.uuid_attribute).unwrap_or_default();
+        if profiles.is_empty() {
+            warn!("ldap entry {} has no {}", entry.dn, self.uuid_attribute);
+            return Ok(None);
+        }
+        Ok(Some(Account { profiles, enabled: true, expires_at: None, notes: None }))
+    }
+}
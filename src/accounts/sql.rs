@@ -0,0 +1,89 @@
+use sqlx::{
+    AnyPool, Row,
+    any::{AnyPoolOptions, AnyRow},
+};
+
+use super::{Account, AccountError};
+use crate::{
+    config::{SqlConfig, redact_url},
+    unix_now,
+};
+
+// sqlx can't decode SQLite's BOOLEAN through the Any driver, the cast makes
+// it an integer on both databases.
+const DEFAULT_QUERY: &str =
+    "SELECT uuid, CAST(enabled AS INTEGER) AS enabled, expires_at FROM tokens WHERE token = $1";
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS tokens (
+    token TEXT NOT NULL,
+    uuid TEXT NOT NULL,
+    enabled BOOLEAN NOT NULL DEFAULT TRUE,
+    expires_at BIGINT,
+    notes TEXT,
+    PRIMARY KEY (token, uuid)
+)";
+
+/// Looks tokens up in SQLite or PostgreSQL. Each row returned for a token
+/// is one allowed profile; rows that are disabled or past `expires_at` are
+/// skipped.
+pub struct SqlAccounts {
+    pool: AnyPool,
+    query: String,
+}
+
+impl SqlAccounts {
+    pub async fn connect(config: &SqlConfig) -> Result<Self, String> {
+        sqlx::any::install_default_drivers();
+        let pool = AnyPoolOptions::new()
+            .max_connections(config.max_connections)
+            .connect(&config.url)
+            .await
+            .map_err(|e| format!("failed to connect to {}: {e}", redact_url(&config.url)))?;
+        if config.query.is_none() && config.create_schema {
+            sqlx::query(SCHEMA)
+                .execute(&pool)
+                .await
+                .map_err(|e| format!("failed to create tokens table: {e}"))?;
+        }
+        Ok(SqlAccounts {
+            pool,
+            query: config.query.clone().unwrap_or_else(|| DEFAULT_QUERY.to_string()),
+        })
+    }
+
+    pub async fn get(&self, token: &str) -> Result<Option<Account>, AccountError> {
+        let rows = sqlx::query(&self.query).bind(token).fetch_all(&self.pool).await?;
+        if rows.is_empty() {
+            return Ok(None);
+        }
+        let now = unix_now();
+        let mut profiles = Vec::new();
+        for row in &rows {
+            if enabled(row)? && expires_at(row)?.is_none_or(|t| now < t) {
+                profiles.push(row.try_get("uuid")?);
+            }
+        }
+        // Known token, but nothing it may currently join as.
+        let enabled = !profiles.is_empty();
+        Ok(Some(Account { profiles, enabled, expires_at: None, notes: None }))
+    }
+}
+
+// Custom queries may leave out the optional columns, which then take their
+// defaults. Any other decoding failure is an error, so a NULL or mistyped
+// column never lets a token through. SQLite hands booleans back as integers.
+fn enabled(row: &AnyRow) -> Result<bool, sqlx::Error> {
+    match row.try_get::<bool, _>("enabled") {
+        Ok(enabled) => Ok(enabled),
+        Err(sqlx::Error::ColumnNotFound(_)) => Ok(true),
+        Err(e) => row.try_get::<i64, _>("enabled").map(|v| v != 0).map_err(|_| e),
+    }
+}
+
+fn expires_at(row: &AnyRow) -> Result<Option<u64>, sqlx::Error> {
+    match row.try_get::<Option<i64>, _>("expires_at") {
+        Ok(expires_at) => Ok(expires_at.map(|t| t as u64)),
+        Err(sqlx::Error::ColumnNotFound(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
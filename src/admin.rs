@@ -0,0 +1,208 @@
+//! Authenticated admin API for managing accounts and the server blocklist at
+//! runtime. Only mounted when `admin.token` is configured; callers send it as
+//! a bearer token.
+
+use axum::{
+    Json, Router,
+    extract::{Path, Request, State},
+    http::{StatusCode, header::AUTHORIZATION},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{error, info};
+
+use crate::{
+    AppState,
+    accounts::{Account, AccountRecord, FileAccounts, parse_uuid},
+    blocklist::BlockedServer,
+    tokens,
+};
+
+pub fn router(state: AppState) -> Router<AppState> {
+    Router::new()
+        .route("/admin/accounts", get(list_accounts).post(add_account))
+        // Tokens travel in the body so they stay out of access logs.
+        .route("/admin/accounts/lookup", post(lookup_account))
+        .route("/admin/accounts/revoke", post(revoke_account))
+        .route("/admin/blockedservers", get(list_blocked).post(block_server))
+        .route("/admin/blockedservers/{pattern}", delete(unblock_server))
+        .route_layer(middleware::from_fn_with_state(state, require_admin))
+}
+
+async fn require_admin(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let expected = state.admin_token.as_deref().unwrap_or_default();
+    let provided = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match provided {
+        Some(token) if !expected.is_empty() && tokens::constant_time_eq(token.as_bytes(), expected.as_bytes()) => {
+            next.run(request).await
+        }
+        _ => AdminError(StatusCode::UNAUTHORIZED, "missing or invalid admin token".to_string()).into_response(),
+    }
+}
+
+struct AdminError(StatusCode, String);
+
+impl IntoResponse for AdminError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+fn file_accounts(state: &AppState) -> Result<&FileAccounts, AdminError> {
+    state.accounts.file_accounts().ok_or_else(|| {
+        AdminError(StatusCode::NOT_IMPLEMENTED, "account management requires a file backend".to_string())
+    })
+}
+
+#[derive(Serialize)]
+struct AccountEntry {
+    token: String,
+    profiles: Vec<String>,
+    enabled: bool,
+    expires_at: Option<u64>,
+    notes: Option<String>,
+}
+
+impl AccountEntry {
+    fn new(token: String, account: Account) -> Self {
+        AccountEntry {
+            token,
+            profiles: account.profiles,
+            enabled: account.enabled,
+            expires_at: account.expires_at,
+            notes: account.notes,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct NewAccount {
+    /// Generated when omitted.
+    token: Option<String>,
+    /// `uuid` and/or `profiles`, plus the optional record fields.
+    #[serde(flatten)]
+    record: AccountRecord,
+}
+
+#[derive(Deserialize)]
+struct TokenRequest {
+    token: String,
+}
+
+async fn list_accounts(State(state): State<AppState>) -> Result<Json<Vec<AccountEntry>>, AdminError> {
+    let accounts = file_accounts(&state)?;
+    let mut entries: Vec<_> = accounts
+        .list()
+        .await
+        .into_iter()
+        .map(|(token, account)| AccountEntry::new(token, account))
+        .collect();
+    entries.sort_by(|a, b| a.profiles.cmp(&b.profiles));
+    Ok(Json(entries))
+}
+
+async fn lookup_account(
+    State(state): State<AppState>,
+    Json(request): Json<TokenRequest>,
+) -> Result<Json<AccountEntry>, AdminError> {
+    let accounts = file_accounts(&state)?;
+    match accounts.get(&request.token).await {
+        Some(account) => Ok(Json(AccountEntry::new(request.token, account))),
+        None => Err(AdminError(StatusCode::NOT_FOUND, "unknown token".to_string())),
+    }
+}
+
+async fn add_account(
+    State(state): State<AppState>,
+    Json(new): Json<NewAccount>,
+) -> Result<(StatusCode, Json<AccountEntry>), AdminError> {
+    let accounts = file_accounts(&state)?;
+    let mut account = Account::from(new.record);
+    for profile in &mut account.profiles {
+        *profile = parse_uuid(profile)
+            .ok_or_else(|| AdminError(StatusCode::BAD_REQUEST, format!("invalid uuid {profile:?}")))?;
+    }
+    if account.profiles.is_empty() {
+        return Err(AdminError(StatusCode::BAD_REQUEST, "uuid or profiles is required".to_string()));
+    }
+    let token = new.token.filter(|t| !t.is_empty()).unwrap_or_else(tokens::generate);
+
+    let previous = accounts.insert(&token, account.clone()).await.map_err(|e| {
+        error!("failed to write accounts file: {e}");
+        AdminError(StatusCode::INTERNAL_SERVER_ERROR, "failed to persist accounts".to_string())
+    })?;
+    info!("admin: mapped a token to {account}");
+    let status = if previous.is_some() { StatusCode::OK } else { StatusCode::CREATED };
+    Ok((status, Json(AccountEntry::new(token, account))))
+}
+
+async fn revoke_account(
+    State(state): State<AppState>,
+    Json(request): Json<TokenRequest>,
+) -> Result<StatusCode, AdminError> {
+    let accounts = file_accounts(&state)?;
+    match accounts.remove(&request.token).await {
+        Ok(Some(account)) => {
+            info!("admin: revoked a token for {account}");
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Ok(None) => Err(AdminError(StatusCode::NOT_FOUND, "unknown token".to_string())),
+        Err(e) => {
+            error!("failed to write accounts file: {e}");
+            Err(AdminError(StatusCode::INTERNAL_SERVER_ERROR, "failed to persist accounts".to_string()))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct NewBlock {
+    /// Address pattern or SHA-1 hash.
+    pattern: String,
+}
+
+async fn list_blocked(State(state): State<AppState>) -> Json<Vec<BlockedServer>> {
+    Json(state.blocklist.list().await)
+}
+
+async fn block_server(
+    State(state): State<AppState>,
+    Json(new): Json<NewBlock>,
+) -> Result<(StatusCode, Json<BlockedServer>), AdminError> {
+    if new.pattern.trim().is_empty() {
+        return Err(AdminError(StatusCode::BAD_REQUEST, "pattern is required".to_string()));
+    }
+    let entry = BlockedServer::parse(&new.pattern);
+    let added = state.blocklist.add(entry.clone()).await.map_err(|e| {
+        error!("failed to write blocklist: {e}");
+        AdminError(StatusCode::INTERNAL_SERVER_ERROR, "failed to persist blocklist".to_string())
+    })?;
+    if added {
+        info!("admin: blocked {}", new.pattern.trim());
+    }
+    let status = if added { StatusCode::CREATED } else { StatusCode::OK };
+    Ok((status, Json(entry)))
+}
+
+async fn unblock_server(
+    State(state): State<AppState>,
+    Path(pattern): Path<String>,
+) -> Result<StatusCode, AdminError> {
+    match state.blocklist.remove(&pattern).await {
+        Ok(removed) if removed.is_empty() => Err(AdminError(StatusCode::NOT_FOUND, "not blocked".to_string())),
+        Ok(_) => {
+            info!("admin: unblocked {pattern}");
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Err(e) => {
+            error!("failed to write blocklist: {e}");
+            Err(AdminError(StatusCode::INTERNAL_SERVER_ERROR, "failed to persist blocklist".to_string()))
+        }
+    }
+}
@@ -0,0 +1,159 @@
+//! `/blockedservers`: the session server's list of blocked server hashes,
+//! cached, merged with a local list managed through the admin API and the
+//! `block` / `unblock` subcommands.
+//!
+//! Clients check the SHA-1 of a server's lowercase address, and of its
+//! wildcard forms such as `*.example.com`, against these hashes.
+
+use axum::{
+    extract::State,
+    http::{StatusCode, header::CONTENT_TYPE},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::{
+    collections::BTreeSet,
+    io,
+    path::Path,
+    time::Duration,
+};
+use tracing::info;
+
+use crate::{
+    AppState,
+    config::BlocklistConfig,
+    persist::{self, JsonFile},
+    upstream::{Cached, Upstream},
+};
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct BlockedServer {
+    /// Hex SHA-1 of the pattern.
+    pub hash: String,
+    /// The pattern itself, unless only its hash was given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+}
+
+impl BlockedServer {
+    /// `value` as is if it is already a SHA-1 hash, otherwise a pattern
+    /// such as `mc.example.com` or `*.example.com` to hash.
+    pub fn parse(value: &str) -> Self {
+        let value = value.trim().to_lowercase();
+        if value.len() == 40 && value.chars().all(|c| c.is_ascii_hexdigit()) {
+            return BlockedServer { hash: value, pattern: None };
+        }
+        BlockedServer { hash: hex::encode(Sha1::digest(value.as_bytes())), pattern: Some(value) }
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        let value = value.trim().to_lowercase();
+        self.hash == value || self.pattern.as_deref() == Some(value.as_str())
+    }
+}
+
+/// Reads the local list, empty if it doesn't exist yet.
+pub fn read(path: &Path) -> Vec<BlockedServer> {
+    persist::read_json_or_recover(path)
+}
+
+/// Appends `entry` unless its hash is listed already. Returns whether it was.
+pub fn insert(entries: &mut Vec<BlockedServer>, entry: BlockedServer) -> bool {
+    if entries.iter().any(|e| e.hash == entry.hash) {
+        return false;
+    }
+    entries.push(entry);
+    true
+}
+
+/// Removes the entries whose pattern or hash is `value`, returning them.
+pub fn remove(entries: &mut Vec<BlockedServer>, value: &str) -> Vec<BlockedServer> {
+    let (removed, kept) = entries.drain(..).partition(|e| e.matches(value));
+    *entries = kept;
+    removed
+}
+
+pub fn to_json(entries: &[BlockedServer]) -> io::Result<Vec<u8>> {
+    Ok(serde_json::to_vec_pretty(entries)?)
+}
+
+pub struct Blocklist {
+    merge_upstream: bool,
+    /// The local list, kept in `blocklist.file`.
+    entries: JsonFile<Vec<BlockedServer>>,
+    /// The session server's list.
+    upstream: Cached<Vec<String>>,
+}
+
+impl Blocklist {
+    pub fn new(config: &BlocklistConfig) -> Self {
+        Blocklist {
+            merge_upstream: config.upstream,
+            entries: JsonFile::new(config.file.clone(), read(&config.file)),
+            upstream: Cached::new("blocked servers", Duration::from_secs(config.ttl)),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        self.entries.path()
+    }
+
+    pub async fn list(&self) -> Vec<BlockedServer> {
+        self.entries.read().await.clone()
+    }
+
+    /// Re-reads the file, e.g. after `block` or `unblock` changed it, keeping
+    /// the current list if it doesn't parse.
+    pub async fn reload(&self) -> Result<(), String> {
+        let old = self.entries.reload(persist::read_json).await?;
+        let new = self.entries.read().await;
+        let hashes = |entries: &[BlockedServer]| entries.iter().map(|e| e.hash.clone()).collect::<BTreeSet<_>>();
+        if hashes(&new) != hashes(&old) {
+            info!("reloaded {}: {} blocked servers", self.path().display(), new.len());
+        }
+        Ok(())
+    }
+
+    /// Adds `entry` to the local list. Returns whether it wasn't there yet.
+    pub async fn add(&self, entry: BlockedServer) -> io::Result<bool> {
+        let added = self.entries.update(|entries| insert(entries, entry).then_some(())).await?;
+        Ok(added.is_some())
+    }
+
+    /// Removes the local entries matching `value`, returning them.
+    pub async fn remove(&self, value: &str) -> io::Result<Vec<BlockedServer>> {
+        let removed = self
+            .entries
+            .update(|entries| {
+                let removed = remove(entries, value);
+                (!removed.is_empty()).then_some(removed)
+            })
+            .await?;
+        Ok(removed.unwrap_or_default())
+    }
+
+    /// The session server's hashes, refetched once older than `ttl`. The
+    /// last list fetched is kept while it can't be reached.
+    async fn upstream_hashes(&self, upstream: &Upstream) -> Vec<String> {
+        let fetch = || async {
+            let resp = upstream.blocked_servers().await.map_err(|e| e.to_string())?;
+            if resp.status() != StatusCode::OK {
+                return Err(format!("answered {}", resp.status()));
+            }
+            let body = resp.text().await.map_err(|e| e.to_string())?;
+            Ok(body.lines().map(str::trim).filter(|l| !l.is_empty()).map(String::from).collect())
+        };
+        self.upstream.get(fetch).await.unwrap_or_default()
+    }
+}
+
+pub async fn handler(State(state): State<AppState>) -> Response {
+    let blocklist = &state.blocklist;
+    let mut hashes: BTreeSet<String> = blocklist.entries.read().await.iter().map(|e| e.hash.clone()).collect();
+    if blocklist.merge_upstream {
+        hashes.extend(blocklist.upstream_hashes(&state.upstream).await);
+    }
+    let body: String = hashes.into_iter().map(|hash| hash + "\n").collect();
+    ([(CONTENT_TYPE, "text/plain")], body).into_response()
+}
@@ -0,0 +1,105 @@
+//! One-off maintenance subcommands that run instead of the server.
+
+use std::{collections::HashMap, fs, path::Path};
+use tracing::{info, warn};
+
+use crate::{
+    accounts::FileAccounts,
+    blocklist::{self, BlockedServer},
+    config::{Command, Config},
+    persist, tokens,
+};
+
+pub fn run(command: &Command, config: &Config) -> Result<(), String> {
+    match command {
+        Command::HashToken { token } => hash_token(token.as_deref(), config),
+        Command::MigrateAccounts => migrate_accounts(config),
+        Command::Block { pattern } => block(pattern, config),
+        Command::Unblock { pattern } => unblock(pattern, config),
+    }
+}
+
+fn pepper(config: &Config) -> &str {
+    let pepper = config.accounts.pepper.as_deref().unwrap_or_default();
+    if pepper.is_empty() {
+        warn!("no accounts.pepper configured, hashes only rely on their salt");
+    }
+    pepper
+}
+
+fn hash_token(token: Option<&str>, config: &Config) -> Result<(), String> {
+    let pepper = pepper(config);
+    match token {
+        Some(token) => println!("{}", tokens::hash(token, pepper)),
+        None => {
+            let token = tokens::generate();
+            println!("token: {token}");
+            println!("hash:  {}", tokens::hash(&token, pepper));
+        }
+    }
+    Ok(())
+}
+
+fn migrate_accounts(config: &Config) -> Result<(), String> {
+    let path = &config.accounts.file;
+    let pepper = pepper(config);
+    let accounts = FileAccounts::read(path)?;
+
+    let mut migrated = 0;
+    let accounts: HashMap<_, _> = accounts
+        .into_iter()
+        .map(|(key, uuid)| {
+            let key = if tokens::is_hashed(&key) {
+                key
+            } else {
+                migrated += 1;
+                tokens::hash(&key, pepper)
+            };
+            (key, uuid)
+        })
+        .collect();
+    if migrated == 0 {
+        info!("{} has no plaintext tokens", path.display());
+        return Ok(());
+    }
+
+    let contents = serde_json::to_vec_pretty(&accounts).map_err(|e| e.to_string())?;
+    persist::write_atomic(path, &contents).map_err(|e| format!("failed to write {}: {e}", path.display()))?;
+    // The backup still holds the plaintext tokens.
+    let backup = persist::backup_path(path);
+    fs::remove_file(&backup).map_err(|e| format!("failed to remove {}: {e}", backup.display()))?;
+    info!("hashed {migrated} tokens in {}", path.display());
+    Ok(())
+}
+
+fn write_blocklist(path: &Path, entries: &[BlockedServer]) -> Result<(), String> {
+    blocklist::to_json(entries)
+        .and_then(|contents| persist::write_atomic(path, &contents))
+        .map_err(|e| format!("failed to write {}: {e}", path.display()))
+}
+
+fn block(pattern: &str, config: &Config) -> Result<(), String> {
+    let path = &config.blocklist.file;
+    let mut entries = blocklist::read(path);
+    let entry = BlockedServer::parse(pattern);
+    let hash = entry.hash.clone();
+    if !blocklist::insert(&mut entries, entry) {
+        info!("{pattern} is already blocked");
+        return Ok(());
+    }
+    write_blocklist(path, &entries)?;
+    info!("blocked {pattern} ({hash}) in {}", path.display());
+    Ok(())
+}
+
+fn unblock(pattern: &str, config: &Config) -> Result<(), String> {
+    let path = &config.blocklist.file;
+    let mut entries = blocklist::read(path);
+    let removed = blocklist::remove(&mut entries, pattern);
+    if removed.is_empty() {
+        return Err(format!("{pattern} is not in {}", path.display()));
+    }
+    write_blocklist(path, &entries)?;
+    info!("unblocked {pattern} in {}", path.display());
+    Ok(())
+}
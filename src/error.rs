@@ -0,0 +1,148 @@
+//! Errors from the session handlers, answered with a Yggdrasil-style body:
+//! `{"error": "...", "errorMessage": "..."}`.
+
+use axum::{
+    Json,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+use std::error::Error as _;
+use tracing::{error, warn};
+use trust_dns_resolver::error::ResolveError;
+
+use crate::{sessions::StoreError, upstream::UpstreamError};
+
+#[derive(Debug, thiserror::Error)]
+pub enum HandlerError {
+    #[error("not a valid UUID: {0:?}")]
+    InvalidUuid(String),
+    #[error("failed to resolve the session server: {0}")]
+    Dns(String),
+    #[error("session server timed out")]
+    Timeout,
+    #[error("session server unreachable: {0}")]
+    Unreachable(String),
+    #[error("session server skipped, circuit open")]
+    CircuitOpen,
+    #[error("session server lookup skipped, rate limited")]
+    RateLimited,
+    #[error("session server answered {0}")]
+    Status(StatusCode),
+    #[error("malformed response from the session server: {0}")]
+    Malformed(String),
+    #[error("no public keys fetched from the services API yet")]
+    NoPublicKeys,
+    #[error("session store failed: {0}")]
+    Store(#[from] StoreError),
+}
+
+impl From<reqwest::Error> for HandlerError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            HandlerError::Timeout
+        } else if e.is_decode() || e.is_body() {
+            HandlerError::Malformed(describe(&e))
+        } else if is_dns(&e) {
+            HandlerError::Dns(describe(&e))
+        } else {
+            HandlerError::Unreachable(describe(&e))
+        }
+    }
+}
+
+impl From<UpstreamError> for HandlerError {
+    fn from(e: UpstreamError) -> Self {
+        match e {
+            UpstreamError::Request(e) => e.into(),
+            UpstreamError::CircuitOpen => HandlerError::CircuitOpen,
+        }
+    }
+}
+
+fn sources(e: &reqwest::Error) -> impl Iterator<Item = &(dyn std::error::Error + 'static)> {
+    std::iter::successors(e.source(), |&e| e.source())
+}
+
+fn is_dns(e: &reqwest::Error) -> bool {
+    sources(e).any(|e| e.is::<ResolveError>())
+}
+
+/// `e` and its causes, since reqwest's own message is only
+/// "error sending request".
+fn describe(e: &reqwest::Error) -> String {
+    sources(e).fold(e.to_string(), |message, cause| format!("{message}: {cause}"))
+}
+
+impl HandlerError {
+    /// Status, Yggdrasil error name and a message safe to show clients; the
+    /// log has the details.
+    fn parts(&self) -> (StatusCode, &'static str, &'static str) {
+        match self {
+            HandlerError::InvalidUuid(_) => {
+                (StatusCode::BAD_REQUEST, "IllegalArgumentException", "Not a valid UUID")
+            }
+            HandlerError::Dns(_) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "ServiceUnavailableException",
+                "Failed to resolve the session server",
+            ),
+            HandlerError::Unreachable(_) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "ServiceUnavailableException",
+                "The session server is unreachable",
+            ),
+            HandlerError::CircuitOpen => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "ServiceUnavailableException",
+                "The session server is unavailable",
+            ),
+            HandlerError::RateLimited => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "TooManyRequestsException",
+                "The session server is rate limiting requests",
+            ),
+            HandlerError::Timeout => {
+                (StatusCode::GATEWAY_TIMEOUT, "GatewayTimeoutException", "The session server timed out")
+            }
+            HandlerError::Status(StatusCode::SERVICE_UNAVAILABLE) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "ServiceUnavailableException",
+                "The session server is unavailable",
+            ),
+            HandlerError::Status(StatusCode::GATEWAY_TIMEOUT) => {
+                (StatusCode::GATEWAY_TIMEOUT, "GatewayTimeoutException", "The session server timed out")
+            }
+            // A 500 or 501 from the session server is not an error of ours.
+            HandlerError::Status(_) => {
+                (StatusCode::BAD_GATEWAY, "BadGatewayException", "The session server failed to answer")
+            }
+            HandlerError::Malformed(_) => (
+                StatusCode::BAD_GATEWAY,
+                "BadGatewayException",
+                "The session server sent a malformed response",
+            ),
+            HandlerError::NoPublicKeys => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "ServiceUnavailableException",
+                "The services API's public keys are unavailable",
+            ),
+            HandlerError::Store(_) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "InternalServerErrorException",
+                "Failed to access the session store",
+            ),
+        }
+    }
+}
+
+impl IntoResponse for HandlerError {
+    fn into_response(self) -> Response {
+        match &self {
+            HandlerError::Store(_) => error!("{self}"),
+            _ => warn!("{self}"),
+        }
+        let (status, error, message) = self.parts();
+        (status, Json(json!({ "error": error, "errorMessage": message }))).into_response()
+    }
+}
@@ -1,252 +1,407 @@
 use axum::{
     Router,
     extract::Json,
+    extract::Path,
     extract::Query,
     extract::State,
+    http::{StatusCode, header::CONTENT_TYPE},
+    response::{IntoResponse, Response},
     routing::{get, post},
 };
-use clap::{Parser};
-use reqwest::Client;
+use accounts::AccountServerKind;
+use blocklist::Blocklist;
+use clap::Parser;
+use config::{Args, Config, SessionStoreKind};
+use error::HandlerError;
 use serde::{Deserialize, Serialize};
+use sessions::{JsonSessionStore, SessionStore, SqliteSessionStore};
 use std::{
-    collections::HashMap, fs, net::IpAddr, path::PathBuf, sync::Arc, time::{SystemTime, UNIX_EPOCH}
+    collections::HashMap,
+    sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}
 };
-use tokio::sync::Mutex;
-use trust_dns_resolver::{TokioAsyncResolver, config::*};
+use profiles::ProfileCache;
+use textures::Signer;
+use tracing::{error, info};
+use tracing_subscriber::EnvFilter;
+use upstream::Upstream;
+
+mod accounts;
+mod admin;
+mod blocklist;
+mod commands;
+mod config;
+mod error;
+mod persist;
+mod profiles;
+mod reload;
+mod sessions;
+mod textures;
+mod tokens;
+mod upstream;
 
 #[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
 struct JoinRequest {
-    selectedProfile: String,
-    serverId: String,
+    selected_profile: String,
+    server_id: String,
     #[serde(default)]
-    authString: Option<String>,
+    auth_string: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
 struct HasJoinedQuery {
     username: String,
-    serverId: String,
+    server_id: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Session {
-    uuid: String,
-    servers: HashMap<String, u64>
+#[derive(Debug, Deserialize)]
+struct ProfileQuery {
+    unsigned: Option<String>,
 }
 
-type SessionMap = Arc<Mutex<HashMap<String, Session>>>;
+type SessionMap = Arc<dyn SessionStore>;
 type AccountMap = Arc<AccountServerKind>;
 
-enum AccountServerKind {
-    API {
-        secret: Option<String>,
-        endpoint: String
-    },
-    File(HashMap<String, String>)
+#[derive(Clone)]
+struct AppState {
+    accounts: AccountMap,
+    sessions: SessionMap,
+    session_ttl: u64,
+    one_shot_sessions: bool,
+    upstream: Arc<Upstream>,
+    profiles: Arc<ProfileCache>,
+    offline_fallback: bool,
+    blocklist: Arc<Blocklist>,
+    /// Signs the textures of local profiles.
+    signer: Option<Arc<Signer>>,
+    admin_token: Option<String>,
 }
 
-#[derive(Deserialize)]
-struct Res {
-    uuid: String
-}
 
-impl AccountServerKind {
-    async fn get(&self, k: &str) -> Option<String> {
-        match self {
-            AccountServerKind::API { secret, endpoint } => {
-                let client = Client::new();
-                let mut req = client
-                    .get(format!("{endpoint}?token={k}"));
-                if let Some(s) = secret {
-                    req = req.bearer_auth(s);
-                }
-                let resp = req.send().await;
-                let Ok(resp) = resp else {
-                    return None;
-                };
-                resp.json::<Res>().await.map(|r| r.uuid).ok()
-            }
-            AccountServerKind::File(hash_map) => {
-                hash_map.get(k).cloned()
-            }
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    let config = match Config::load(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {e}");
+            std::process::exit(1);
+        }
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(&config.logging.level))
+        .init();
+
+    if let Some(command) = &args.command {
+        if let Err(e) = commands::run(command, &config) {
+            error!("{e}");
+            std::process::exit(1);
         }
+        return;
     }
-}
 
-#[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
-struct Args {
-    /// Name of the person to greet
-    #[arg(short, long)]
-    accounts: Option<PathBuf>,
+    let local_profiles = match &config.profiles.file {
+        Some(path) => match profiles::read_local(path) {
+            Ok(local) => local,
+            Err(e) => {
+                error!("{e}");
+                std::process::exit(1);
+            }
+        },
+        None => HashMap::new(),
+    };
 
-    #[arg(short, long)]
-    sessions: Option<PathBuf>,
+    if args.check_config {
+        if let Err(e) = AccountServerKind::check(&config.accounts) {
+            error!("{e}");
+            std::process::exit(1);
+        }
+        print!("{}", toml::to_string_pretty(&config.redacted()).unwrap());
+        return;
+    }
 
-    #[arg(short, long)]
-    endpoint: Option<String>,
+    let accounts = match AccountServerKind::load(&config.accounts).await {
+        Ok(accounts) => Arc::new(accounts),
+        Err(e) => {
+            error!("{e}");
+            std::process::exit(1);
+        }
+    };
 
-    #[arg(long)]
-    api: bool,
-}
+    let signer = match &config.signing.key {
+        Some(path) => match Signer::load_or_generate(path) {
+            Ok(signer) => Some(Arc::new(signer)),
+            Err(e) => {
+                error!("{e}");
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
 
-#[tokio::main]
-async fn main() {
-    let args = Args::parse();
-    let accounts = if args.api {
-        AccountServerKind::API { secret: None, endpoint: args.endpoint.unwrap() }
-    } else {
-        AccountServerKind::File(serde_json::from_str(&fs::read_to_string(args.accounts.unwrap()).unwrap()).unwrap())
+    reload::spawn(accounts.clone(), config.accounts.watch);
+
+    let sessions_path = config.sessions_path();
+    let sessions: SessionMap = match config.sessions.store {
+        SessionStoreKind::Json => Arc::new(JsonSessionStore::open(sessions_path)),
+        SessionStoreKind::Sqlite => match SqliteSessionStore::open(sessions_path).await {
+            Ok(store) => Arc::new(store),
+            Err(e) => {
+                error!("failed to open session database: {e}");
+                std::process::exit(1);
+            }
+        },
     };
-    let accounts = Arc::new(accounts);
-    let sessions_path = args.sessions.unwrap_or(PathBuf::from("sessions.json"));
-    let sessions: SessionMap = Arc::new(Mutex::new(
-        serde_json::from_str(&fs::read_to_string(sessions_path).unwrap()).unwrap_or_default(),
+
+    spawn_session_reaper(
+        sessions.clone(),
+        Duration::from_secs(config.sessions.reap_interval),
+        config.sessions.ttl,
+    );
+
+    let profiles = Arc::new(ProfileCache::open(
+        config.profile_cache.path.clone(),
+        config.profile_cache.ttl,
+        local_profiles,
     ));
+    spawn_profile_flusher(profiles.clone(), Duration::from_secs(config.profile_cache.flush_interval));
+
+    let blocklist = Arc::new(Blocklist::new(&config.blocklist));
+    reload::spawn_blocklist(blocklist.clone());
+
+    let state = AppState {
+        accounts,
+        sessions,
+        session_ttl: config.sessions.ttl,
+        one_shot_sessions: config.sessions.one_shot,
+        upstream: Arc::new(Upstream::new(&config.upstream)),
+        profiles: profiles.clone(),
+        offline_fallback: config.upstream.offline_fallback,
+        blocklist,
+        signer,
+        admin_token: config.admin.token.clone(),
+    };
 
-    let app = Router::new()
+    let session = Router::new()
         .route("/session/minecraft/join", post(join_handler))
         .route("/session/minecraft/hasJoined", get(has_joined_handler))
-        .with_state((accounts, sessions));
+        .route("/session/minecraft/profile/{uuid}", get(profile_handler))
+        .route("/blockedservers", get(blocklist::handler));
+    // authlib-injector prefixes session server paths with `/sessionserver`.
+    let mut app = session.clone().nest("/sessionserver", session);
+    if state.admin_token.is_some() {
+        app = app.merge(admin::router(state.clone()));
+    }
+    if state.signer.is_some() {
+        app = app.merge(textures::router());
+    }
+    let app = app.with_state(state);
 
-    let address = std::env::var("YGG_BIND_ADDRESS").unwrap_or("0.0.0.0:3012".to_string());
-    let listener = tokio::net::TcpListener::bind(address)
+    let listener = tokio::net::TcpListener::bind(&config.bind_address)
         .await
-        .expect("failed to parse tcp port");
+        .expect("failed to bind tcp listener");
+    info!("listening on {}", config.bind_address);
     axum::serve(listener, app.into_make_service())
+        .with_graceful_shutdown(shutdown_signal())
         .await
         .unwrap();
+
+    // Whatever the flusher hasn't written yet would be lost otherwise.
+    if let Err(e) = profiles.flush().await {
+        error!("failed to persist profile cache: {e}");
+    }
 }
 
-async fn resolve_mojang_ip() -> IpAddr {
-    let resolver = TokioAsyncResolver::tokio(ResolverConfig::cloudflare(), ResolverOpts::default());
+/// Resolves on ctrl-c, or SIGTERM from the service manager.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("failed to listen for ctrl-c: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                error!("failed to listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-    let response = resolver
-        .lookup_ip("sessionserver.mojang.com.")
-        .await
-        .unwrap();
-    response.iter().next().unwrap()
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+    info!("shutting down");
 }
 
-async fn join_handler(
-    State((accounts, sessions)): State<(AccountMap, SessionMap)>,
-    Json(payload): Json<JoinRequest>,
-) -> axum::http::StatusCode {
-    println!("{} ({:?}) joining {}", &payload.selectedProfile, &payload.authString, &payload.serverId);
-    let ip = resolve_mojang_ip().await;
-    let url = format!("https://{}/session/minecraft/", ip);
-    let client = Client::builder()
-        .danger_accept_invalid_certs(true)
-        .build()
-        .unwrap();
-    let now = SystemTime::now()
+fn unix_now() -> u64 {
+    SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
-        .as_secs();
-
-    if let Some(auth) = payload.authString {
-        if let Some(stored_profile) = accounts.get(&auth).await {
-            if stored_profile == payload.selectedProfile {
-                let profile: serde_json::Value = client
-                    .get(format!("{}profile/{}", url, payload.selectedProfile))
-                    .header("Host", "sessionserver.mojang.com")
-                    .send()
-                    .await
-                    .unwrap()
-                    .json()
-                    .await
-                    .unwrap();
-
-                if let Some(name) = profile.get("name").and_then(|v| v.as_str()) {
-                    let username = name.to_lowercase();
-                    let mut sessions = sessions.lock().await;
-
-                    for session in sessions.values_mut() {
-                        session.servers.retain(|_, &mut t| now.saturating_sub(t) <= 60);
-                    }
+        .as_secs()
+}
 
-                    sessions
-                        .entry(username.clone())
-                        .or_insert(
-                            Session { uuid: payload.selectedProfile, servers: HashMap::new() }
-                        );
-
-                    sessions
-                        .get_mut(&username)
-                        .unwrap()
-                        .servers
-                        .insert(payload.serverId, now);
-
-                    fs::write("sessions.json", serde_json::to_string(&*sessions).unwrap()).unwrap();
-                    return axum::http::StatusCode::NO_CONTENT;
-                } else {
-                    return axum::http::StatusCode::SERVICE_UNAVAILABLE;
+/// A join is valid for `ttl` seconds after it was recorded, inclusive.
+fn is_session_valid(timestamp: u64, now: u64, ttl: u64) -> bool {
+    now.saturating_sub(timestamp) <= ttl
+}
+
+fn spawn_session_reaper(sessions: SessionMap, interval: Duration, ttl: u64) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match sessions.expire(unix_now().saturating_sub(ttl)).await {
+                Ok(0) => {}
+                Ok(removed) => {
+                    if let Err(e) = sessions.persist().await {
+                        error!("failed to persist sessions after expiring {removed} entries: {e}");
+                    }
                 }
+                Err(e) => error!("failed to expire sessions: {e}"),
+            }
+        }
+    });
+}
+
+fn spawn_profile_flusher(profiles: Arc<ProfileCache>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = profiles.flush().await {
+                error!("failed to persist profile cache: {e}");
+            }
+        }
+    });
+}
+
+async fn join_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<JoinRequest>,
+) -> Result<Response, HandlerError> {
+    // Never log the auth string, it is enough to join as the player.
+    info!("{} joining {}", &payload.selected_profile, &payload.server_id);
+    let now = unix_now();
+
+    if let Some(auth) = payload.auth_string {
+        let Some(account) = state.accounts.get(&auth).await else {
+            return Ok(StatusCode::UNAUTHORIZED.into_response());
+        };
+        if !account.enabled || account.is_expired(now) {
+            info!("rejecting join as {}: account disabled or expired", payload.selected_profile);
+            return Ok(StatusCode::UNAUTHORIZED.into_response());
+        }
+        if !account.allows_profile(&payload.selected_profile) {
+            info!("rejecting join as {}: profile not allowed for this token", payload.selected_profile);
+            return Ok(StatusCode::UNAUTHORIZED.into_response());
+        }
+
+        let profile = profiles::fetch(&state, &payload.selected_profile, false).await?;
+        match profile.status {
+            StatusCode::OK => {}
+            StatusCode::NO_CONTENT | StatusCode::NOT_FOUND | StatusCode::BAD_REQUEST => {
+                info!("rejecting join as {}: no such profile", payload.selected_profile);
+                return Ok(StatusCode::FORBIDDEN.into_response());
             }
+            StatusCode::TOO_MANY_REQUESTS => return Err(HandlerError::RateLimited),
+            status if status.is_server_error() => return Err(HandlerError::Status(status)),
+            status => return Err(HandlerError::Malformed(format!("profile lookup answered {status}"))),
         }
-        return axum::http::StatusCode::UNAUTHORIZED;
+        let name = serde_json::from_str::<serde_json::Value>(&profile.body)
+            .ok()
+            .and_then(|v| v.get("name")?.as_str().map(str::to_lowercase));
+        let Some(username) = name else {
+            return Err(HandlerError::Malformed(format!("profile {} has no name", payload.selected_profile)));
+        };
+        state
+            .sessions
+            .insert(&username, &payload.selected_profile, &payload.server_id, now)
+            .await?;
+        state.sessions.persist().await?;
+        return Ok(profiles::mark_cached(StatusCode::NO_CONTENT.into_response(), profile.cached));
     }
 
     // Proxy join request
-    let resp = client
-        .post(format!("{}join", url))
-        .header("Host", "sessionserver.mojang.com")
-        .header("Content-Type", "application/json")
-        .json(&payload)
-        .send()
-        .await
-        .unwrap();
+    let resp = state.upstream.post("join", &payload).await?;
 
-    axum::http::StatusCode::from_u16(resp.status().as_u16()).unwrap()
+    Ok(resp.status().into_response())
 }
 
 async fn has_joined_handler(
     Query(query): Query<HasJoinedQuery>,
-    axum::extract::State((_, sessions)): axum::extract::State<(AccountMap, SessionMap)>,
-) -> (axum::http::StatusCode, String) {
-    let ip = resolve_mojang_ip().await;
-    let url = format!("https://{}/session/minecraft/", ip);
-    let client = Client::builder()
-        .danger_accept_invalid_certs(true)
-        .build()
-        .unwrap();
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
+    State(state): State<AppState>,
+) -> Result<Response, HandlerError> {
+    let now = unix_now();
     let username = query.username.to_lowercase();
 
-    let sessions = sessions.lock().await;
-    if let Some(user_sessions) = sessions.get(&username) {
-        if let Some(&timestamp) = user_sessions.servers.get(&query.serverId) {
-            if timestamp >= now - 60 {
-                let uuid = &user_sessions.uuid;
-                let resp = client
-                    .get(format!("{}profile/{}?unsigned=false", url, uuid))
-                    .header("Host", "sessionserver.mojang.com")
-                    .send()
-                    .await
-                    .unwrap();
-                let status = resp.status().as_u16();
-                let body = resp.text().await.unwrap();
-                return (axum::http::StatusCode::from_u16(status).unwrap(), body);
+    let session = if state.one_shot_sessions {
+        state.sessions.take(&username, &query.server_id).await?
+    } else {
+        state.sessions.lookup(&username, &query.server_id).await?
+    };
+
+    if let Some(session) = session
+        && is_session_valid(session.timestamp, now, state.session_ttl)
+    {
+        let profile = profiles::fetch(&state, &session.uuid, true).await;
+        if state.one_shot_sessions {
+            match &profile {
+                Ok(profile) if profile.status == StatusCode::OK => state.sessions.persist().await?,
+                // The server never got the profile, so the join must stay usable for its retry.
+                _ => {
+                    state
+                        .sessions
+                        .insert(&username, &session.uuid, &query.server_id, session.timestamp)
+                        .await?
+                }
             }
         }
+        return Ok(profile?.into_response());
     }
 
-    let resp = client
-        .get(format!(
-            "{}hasJoined?serverId={}&username={}",
-            url, query.serverId, username
-        ))
-        .header("Host", "sessionserver.mojang.com")
-        .send()
-        .await
-        .unwrap();
-    let status = resp.status().as_u16();
-    if let Ok(body) = resp.text().await {
-        (axum::http::StatusCode::from_u16(status).unwrap(), body)
-    } else {
-        unreachable!();
+    let resp = state
+        .upstream
+        .get("hasJoined", &[("serverId", &query.server_id), ("username", &username)])
+        .await?;
+    let status = resp.status();
+    Ok((status, resp.text().await?).into_response())
+}
+
+/// Profiles of self-hosted players are answered locally, everyone else's
+/// come from the session server.
+async fn profile_handler(
+    Path(uuid): Path<String>,
+    Query(query): Query<ProfileQuery>,
+    State(state): State<AppState>,
+) -> Result<Response, HandlerError> {
+    // Decoded from the path, so it must not reach the upstream URL unchecked.
+    let uuid = accounts::parse_uuid(&uuid).ok_or(HandlerError::InvalidUuid(uuid))?;
+    let signed = query.unsigned.as_deref() == Some("false");
+    if state.profiles.contains(&uuid).await {
+        return Ok(profiles::fetch(&state, &uuid, signed).await?.into_response());
+    }
+
+    let mut params = Vec::new();
+    if let Some(unsigned) = &query.unsigned {
+        params.push(("unsigned", unsigned.as_str()));
+    }
+    let resp = state.upstream.get(&format!("profile/{uuid}"), &params).await?;
+    let status = resp.status();
+    let content_type = resp.headers().get(CONTENT_TYPE).cloned();
+    let mut response = (status, resp.text().await?).into_response();
+    if let Some(content_type) = content_type {
+        response.headers_mut().insert(CONTENT_TYPE, content_type);
     }
+    Ok(response)
 }
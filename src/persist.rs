@@ -0,0 +1,272 @@
+use serde::{Serialize, de::DeserializeOwned};
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+use tokio::sync::{RwLock, RwLockReadGuard};
+use tracing::warn;
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Path of the last known good copy kept next to `path`.
+pub fn backup_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".bak")
+}
+
+/// Replaces `path` with `contents` so that readers see either the old or the
+/// new file, never a partial one. The previous version is kept as `.bak`.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp = with_suffix(path, ".tmp");
+    let mut file = File::create(&tmp)?;
+    set_permissions(&file, path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+
+    if path.exists() {
+        rotate_backup(path)?;
+    }
+    fs::rename(&tmp, path)?;
+
+    // Make the rename itself durable.
+    if let Some(dir) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Gives the new file the permissions of the one it replaces, or makes it
+/// private to us if there is none, before anything is written to it.
+#[cfg(unix)]
+fn set_permissions(file: &File, path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let permissions = match fs::metadata(path) {
+        Ok(metadata) => metadata.permissions(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => fs::Permissions::from_mode(0o600),
+        Err(e) => return Err(e),
+    };
+    file.set_permissions(permissions)
+}
+
+#[cfg(not(unix))]
+fn set_permissions(_file: &File, _path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Keeps the current version of `path` as `.bak`. A hard link costs no
+/// copy, and the rename that follows leaves it pointing at the old file.
+fn rotate_backup(path: &Path) -> io::Result<()> {
+    let backup = backup_path(path);
+    match fs::remove_file(&backup) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    if fs::hard_link(path, &backup).is_err() {
+        // Filesystems without hard links.
+        fs::copy(path, &backup)?;
+    }
+    Ok(())
+}
+
+pub async fn write_atomic_async(path: PathBuf, contents: Vec<u8>) -> io::Result<()> {
+    tokio::task::spawn_blocking(move || write_atomic(&path, &contents))
+        .await
+        .map_err(io::Error::other)?
+}
+
+pub fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, String> {
+    let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    serde_json::from_str(&contents).map_err(|e| e.to_string())
+}
+
+/// Loads a JSON file written by `write_atomic`. A missing file yields the
+/// default value; a corrupt one falls back to the `.bak` copy, then to the
+/// default, with a warning either way.
+pub fn read_json_or_recover<T: DeserializeOwned + Default>(path: &Path) -> T {
+    if !path.exists() {
+        return T::default();
+    }
+    let err = match read_json(path) {
+        Ok(value) => return value,
+        Err(e) => e,
+    };
+    let backup = backup_path(path);
+    warn!("failed to load {}: {err}", path.display());
+    match read_json(&backup) {
+        Ok(value) => {
+            warn!("recovered {} from {}", path.display(), backup.display());
+            value
+        }
+        Err(e) => {
+            warn!("no usable backup at {} ({e}), starting empty", backup.display());
+            T::default()
+        }
+    }
+}
+
+/// In-memory copy of a JSON file that is edited at runtime. Every change is
+/// written back with `write_atomic`, and the file can be re-read after it was
+/// edited by hand or by a subcommand.
+pub struct JsonFile<T> {
+    path: PathBuf,
+    value: RwLock<T>,
+}
+
+impl<T> JsonFile<T>
+where
+    T: Serialize + Clone + Send + Sync + 'static,
+{
+    pub fn new(path: PathBuf, value: T) -> Self {
+        JsonFile { path, value: RwLock::new(value) }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.value.read().await
+    }
+
+    /// Re-reads the file with `read` and swaps the result in, returning the
+    /// value it replaced. The current value is kept if `read` fails.
+    pub async fn reload(&self, read: fn(&Path) -> Result<T, String>) -> Result<T, String> {
+        // Held across the read so a write can't land in between.
+        let mut value = self.value.write().await;
+        let path = self.path.clone();
+        let new = tokio::task::spawn_blocking(move || read(&path))
+            .await
+            .map_err(|e| e.to_string())??;
+        Ok(std::mem::replace(&mut value, new))
+    }
+
+    /// Applies `change` and writes the file, unless `change` returns `None`
+    /// because it left the value alone. The change is undone if the file
+    /// can't be written, so the two never disagree.
+    pub async fn update<R>(&self, change: impl FnOnce(&mut T) -> Option<R>) -> io::Result<Option<R>> {
+        let mut value = self.value.write().await;
+        let previous = value.clone();
+        let Some(result) = change(&mut value) else {
+            return Ok(None);
+        };
+        let written = match serde_json::to_vec_pretty(&*value) {
+            Ok(contents) => write_atomic_async(self.path.clone(), contents).await,
+            Err(e) => Err(io::Error::other(e)),
+        };
+        if let Err(e) = written {
+            *value = previous;
+            return Err(e);
+        }
+        Ok(Some(result))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// A fresh path in the temp dir, removed along with its siblings on drop.
+    pub(crate) struct TempPath(pub PathBuf);
+
+    impl TempPath {
+        pub(crate) fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("ygg-test-{}-{name}", std::process::id()));
+            TempPath(path)
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            for suffix in ["", ".tmp", ".bak", "-wal", "-shm"] {
+                let _ = fs::remove_file(with_suffix(&self.0, suffix));
+            }
+        }
+    }
+
+    type Map = HashMap<String, u64>;
+
+    #[test]
+    fn missing_file_reads_as_default() {
+        let path = TempPath::new("persist-missing.json");
+        assert_eq!(read_json_or_recover::<Map>(&path.0), Map::new());
+    }
+
+    #[test]
+    fn corrupt_file_recovers_from_backup() {
+        let path = TempPath::new("persist-recover.json");
+        fs::write(&path.0, b"{\"a\": 1").unwrap();
+        fs::write(backup_path(&path.0), b"{\"a\": 2}").unwrap();
+        assert_eq!(read_json_or_recover::<Map>(&path.0), Map::from([("a".into(), 2)]));
+    }
+
+    #[test]
+    fn corrupt_file_and_backup_read_as_default() {
+        let path = TempPath::new("persist-corrupt.json");
+        fs::write(&path.0, b"{\"a\": 1").unwrap();
+        fs::write(backup_path(&path.0), b"not json").unwrap();
+        assert_eq!(read_json_or_recover::<Map>(&path.0), Map::new());
+    }
+
+    #[test]
+    fn write_keeps_previous_version_as_backup() {
+        let path = TempPath::new("persist-backup.json");
+        write_atomic(&path.0, b"first").unwrap();
+        assert!(!backup_path(&path.0).exists());
+        write_atomic(&path.0, b"second").unwrap();
+        assert_eq!(fs::read(&path.0).unwrap(), b"second");
+        assert_eq!(fs::read(backup_path(&path.0)).unwrap(), b"first");
+        write_atomic(&path.0, b"third").unwrap();
+        assert_eq!(fs::read(backup_path(&path.0)).unwrap(), b"second");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_keeps_mode_of_replaced_file() {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        let path = TempPath::new("persist-mode.json");
+        write_atomic(&path.0, b"first").unwrap();
+        assert_eq!(mode(&path.0), 0o600);
+        fs::set_permissions(&path.0, fs::Permissions::from_mode(0o640)).unwrap();
+        write_atomic(&path.0, b"second").unwrap();
+        assert_eq!(mode(&path.0), 0o640);
+    }
+
+    #[tokio::test]
+    async fn json_file_update_writes_and_rolls_back() {
+        let path = TempPath::new("persist-json-file.json");
+        let file = JsonFile::new(path.0.clone(), Map::new());
+        let inserted = file.update(|map| Some(map.insert("a".into(), 1))).await.unwrap();
+        assert_eq!(inserted, Some(None));
+        assert_eq!(read_json::<Map>(&path.0).unwrap(), Map::from([("a".into(), 1)]));
+        // Nothing changed, nothing written.
+        fs::remove_file(&path.0).unwrap();
+        assert_eq!(file.update(|_| None::<()>).await.unwrap(), None);
+        assert!(!path.0.exists());
+
+        let unwritable = JsonFile::new(path.0.join("missing-dir"), Map::new());
+        assert!(unwritable.update(|map| map.insert("a".into(), 1).or(Some(0))).await.is_err());
+        assert!(unwritable.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn json_file_reload_keeps_value_on_error() {
+        let path = TempPath::new("persist-json-reload.json");
+        let file = JsonFile::new(path.0.clone(), Map::from([("a".into(), 1)]));
+        fs::write(&path.0, b"{\"b\": 2}").unwrap();
+        assert_eq!(file.reload(read_json).await.unwrap(), Map::from([("a".into(), 1)]));
+        assert_eq!(*file.read().await, Map::from([("b".into(), 2)]));
+
+        fs::write(&path.0, b"{\"c\":").unwrap();
+        assert!(file.reload(read_json).await.is_err());
+        assert_eq!(*file.read().await, Map::from([("b".into(), 2)]));
+    }
+}
@@ -0,0 +1,329 @@
+//! Profiles of self-hosted players: those stored locally, and a cache of
+//! those fetched from the session server, keyed by UUID and persisted across
+//! restarts. Fresh cache entries save an upstream call on every join and
+//! hasJoined; older ones still answer while the session server is rate
+//! limiting us or, with offline fallback on, down.
+
+use axum::{
+    http::{
+        HeaderName, HeaderValue, StatusCode,
+        header::{CONTENT_TYPE, RETRY_AFTER},
+    },
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use reqwest::Url;
+use serde_json::{Value, json};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+};
+use tokio::sync::{Mutex, RwLock};
+use tracing::warn;
+
+use crate::{
+    AppState,
+    accounts::normalize_uuid,
+    error::HandlerError,
+    persist,
+    sessions::StoreError,
+    textures::{self, Signer, Textures},
+    unix_now,
+};
+
+/// Seconds to hold off after a 429 that doesn't say how long to wait.
+const DEFAULT_RETRY_AFTER: u64 = 60;
+
+/// Set on responses answered from the profile cache.
+const SERVED_FROM_CACHE: HeaderName = HeaderName::from_static("x-served-from-cache");
+
+#[derive(Deserialize, Serialize, Clone)]
+struct CachedProfile {
+    /// Signed, as returned by `profile/{uuid}?unsigned=false`.
+    profile: Value,
+    fetched_at: u64,
+}
+
+pub struct ProfileCache {
+    path: PathBuf,
+    ttl: u64,
+    /// Profiles served as stored instead of asking the session server.
+    local: HashMap<String, LocalProfile>,
+    /// Keyed by dashless, lowercase UUID.
+    profiles: RwLock<HashMap<String, CachedProfile>>,
+    /// Signed `textures` properties of local profiles and when they were
+    /// signed, reused for `ttl`.
+    signed_textures: RwLock<HashMap<String, (Value, u64)>>,
+    /// Set when `profiles` has changes not written to `path` yet.
+    dirty: AtomicBool,
+    // Serializes file writes without holding the cache lock during IO.
+    write_lock: Mutex<()>,
+    /// No profile lookups go upstream before this time.
+    rate_limited_until: AtomicU64,
+}
+
+/// A profile answered by the session server, stored locally or from the
+/// cache.
+pub struct Profile {
+    pub status: StatusCode,
+    pub body: String,
+    pub cached: bool,
+}
+
+/// A profile kept in `profiles.file`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LocalProfile {
+    /// Defaults to the map key.
+    #[serde(default)]
+    id: Option<String>,
+    name: String,
+    /// Served as they are, e.g. properties signed by the session server.
+    #[serde(default)]
+    properties: Vec<Value>,
+    /// Texture URLs. When either is set, the `textures` property is built
+    /// and signed here instead.
+    skin: Option<String>,
+    #[serde(default)]
+    slim: bool,
+    cape: Option<String>,
+}
+
+impl LocalProfile {
+    /// The value of the `textures` property for `uuid`, if a skin or cape
+    /// is set.
+    fn textures(&self, uuid: &str, signed: bool) -> Option<String> {
+        if self.skin.is_none() && self.cape.is_none() {
+            return None;
+        }
+        let textures = Textures { skin: self.skin.as_deref(), slim: self.slim, cape: self.cape.as_deref() };
+        Some(textures.value(uuid, &self.name, unix_now() * 1000, signed))
+    }
+
+    fn render(&self, uuid: &str, textures: Option<Value>) -> Value {
+        let mut properties = self.properties.clone();
+        if let Some(textures) = textures {
+            properties.retain(|p| p.get("name").and_then(Value::as_str) != Some("textures"));
+            properties.push(textures);
+        }
+        json!({ "id": uuid, "name": self.name, "properties": properties })
+    }
+
+    fn texture_urls(&self) -> impl Iterator<Item = &str> {
+        self.skin.iter().chain(&self.cape).map(String::as_str)
+    }
+}
+
+/// Reads a UUID -> profile map.
+pub fn read_local(path: &Path) -> Result<HashMap<String, LocalProfile>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+    let profiles: HashMap<String, LocalProfile> =
+        serde_json::from_str(&contents).map_err(|e| format!("failed to parse {}: {e}", path.display()))?;
+    Ok(profiles.into_iter().map(|(uuid, profile)| (normalize_uuid(&uuid), profile)).collect())
+}
+
+impl ProfileCache {
+    pub fn open(path: PathBuf, ttl: u64, local: HashMap<String, LocalProfile>) -> Self {
+        let profiles = persist::read_json_or_recover(&path);
+        ProfileCache {
+            path,
+            ttl,
+            local,
+            profiles: RwLock::new(profiles),
+            signed_textures: RwLock::default(),
+            dirty: AtomicBool::new(false),
+            write_lock: Mutex::new(()),
+            rate_limited_until: AtomicU64::new(0),
+        }
+    }
+
+    async fn get(&self, uuid: &str) -> Option<CachedProfile> {
+        self.profiles.read().await.get(&normalize_uuid(uuid)).cloned()
+    }
+
+    /// Whether `uuid` is a self-hosted player, stored locally or cached.
+    pub async fn contains(&self, uuid: &str) -> bool {
+        let uuid = normalize_uuid(uuid);
+        self.local.contains_key(&uuid) || self.profiles.read().await.contains_key(&uuid)
+    }
+
+    /// Hosts serving the textures of local profiles, which authlib-injector
+    /// only loads from domains it was told about.
+    pub fn texture_domains(&self) -> Vec<String> {
+        let mut domains: Vec<String> = self
+            .local
+            .values()
+            .flat_map(LocalProfile::texture_urls)
+            .filter_map(|url| Url::parse(url).ok()?.host_str().map(String::from))
+            .collect();
+        domains.sort();
+        domains.dedup();
+        domains
+    }
+
+    /// The locally stored profile `key`, if there is one, with its textures
+    /// signed when `signed` and a signer is configured.
+    async fn render_local(&self, key: &str, signer: Option<&Arc<Signer>>, signed: bool) -> Option<Value> {
+        let local = self.local.get(key)?;
+        let uuid = local.id.as_deref().unwrap_or(key);
+        let textures = match signer {
+            Some(signer) if signed => self.signed_textures(key, local, uuid, signer).await,
+            _ => local.textures(uuid, false).map(|value| textures::property(value, None)),
+        };
+        Some(local.render(uuid, textures))
+    }
+
+    /// Signing costs milliseconds of CPU, so a signed property is reused for
+    /// `ttl` instead of signing on every join and profile lookup.
+    async fn signed_textures(
+        &self,
+        key: &str,
+        local: &LocalProfile,
+        uuid: &str,
+        signer: &Arc<Signer>,
+    ) -> Option<Value> {
+        let now = unix_now();
+        if let Some((property, signed_at)) = self.signed_textures.read().await.get(key)
+            && now.saturating_sub(*signed_at) < self.ttl
+        {
+            return Some(property.clone());
+        }
+        let value = local.textures(uuid, true)?;
+        let signature = signer.clone().sign_blocking(value.clone()).await;
+        let property = textures::property(value, Some(signature));
+        self.signed_textures.write().await.insert(key.to_string(), (property.clone(), now));
+        Some(property)
+    }
+
+    async fn insert(&self, uuid: &str, profile: Value, now: u64) {
+        let entry = CachedProfile { profile, fetched_at: now };
+        self.profiles.write().await.insert(normalize_uuid(uuid), entry);
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Writes the cache to `path` if it changed since the last flush. Called
+    /// periodically, so lookups never wait on the disk.
+    pub async fn flush(&self) -> Result<(), StoreError> {
+        let _write = self.write_lock.lock().await;
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let contents = serde_json::to_vec(&*self.profiles.read().await)?;
+        if let Err(e) = persist::write_atomic_async(self.path.clone(), contents).await {
+            self.dirty.store(true, Ordering::Relaxed);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    fn is_rate_limited(&self, now: u64) -> bool {
+        now < self.rate_limited_until.load(Ordering::Relaxed)
+    }
+
+    fn rate_limit(&self, until: u64) {
+        self.rate_limited_until.fetch_max(until, Ordering::Relaxed);
+    }
+}
+
+impl Profile {
+    fn cached(entry: CachedProfile) -> Self {
+        Profile { status: StatusCode::OK, body: entry.profile.to_string(), cached: true }
+    }
+
+    /// Drops the property signatures, as the session server does unless
+    /// asked for `unsigned=false`.
+    fn unsigned(mut self) -> Self {
+        if self.status == StatusCode::OK
+            && let Ok(mut profile) = serde_json::from_str::<Value>(&self.body)
+        {
+            if let Some(properties) = profile.get_mut("properties").and_then(Value::as_array_mut) {
+                for property in properties.iter_mut().filter_map(Value::as_object_mut) {
+                    property.remove("signature");
+                }
+            }
+            self.body = profile.to_string();
+        }
+        self
+    }
+}
+
+impl IntoResponse for Profile {
+    fn into_response(self) -> Response {
+        let mut resp = (self.status, self.body).into_response();
+        if self.status == StatusCode::OK {
+            resp.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        }
+        mark_cached(resp, self.cached)
+    }
+}
+
+pub fn mark_cached(mut resp: Response, cached: bool) -> Response {
+    if cached {
+        resp.headers_mut().insert(SERVED_FROM_CACHE, HeaderValue::from_static("true"));
+    }
+    resp
+}
+
+/// Fetches the profile of `uuid`, signed if asked to. Locally stored ones are
+/// served as they are; the cache answers while an entry is fresh, the
+/// session server rate limits us, or it is down and offline fallback is on.
+pub async fn fetch(state: &AppState, uuid: &str, signed: bool) -> Result<Profile, HandlerError> {
+    let local = state.profiles.render_local(&normalize_uuid(uuid), state.signer.as_ref(), signed).await;
+    let profile = match local {
+        Some(profile) => Profile { status: StatusCode::OK, body: profile.to_string(), cached: false },
+        None => fetch_signed(state, uuid).await?,
+    };
+    Ok(if signed { profile } else { profile.unsigned() })
+}
+
+async fn fetch_signed(state: &AppState, uuid: &str) -> Result<Profile, HandlerError> {
+    let cache = &state.profiles;
+    let now = unix_now();
+    let cached = cache.get(uuid).await;
+    if let Some(entry) = &cached
+        && now.saturating_sub(entry.fetched_at) < cache.ttl
+    {
+        return Ok(Profile::cached(entry.clone()));
+    }
+    if cache.is_rate_limited(now) {
+        warn!("session server is rate limiting profile lookups, using the cache for {uuid}");
+        return cached.map(Profile::cached).ok_or(HandlerError::RateLimited);
+    }
+
+    let upstream = state.upstream.get(&format!("profile/{uuid}"), &[("unsigned", "false")]).await;
+    let (degraded, rate_limited) = match &upstream {
+        Ok(resp) if resp.status() == StatusCode::TOO_MANY_REQUESTS => {
+            let retry_after = resp
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|v| v.to_str().ok()?.parse().ok())
+                .unwrap_or(DEFAULT_RETRY_AFTER);
+            warn!("session server rate limited a profile lookup, holding off for {retry_after}s");
+            cache.rate_limit(now + retry_after);
+            (true, true)
+        }
+        Ok(resp) => (resp.status().is_server_error(), false),
+        Err(_) => (true, false),
+    };
+    if let Some(entry) = cached
+        && (rate_limited || (degraded && state.offline_fallback))
+    {
+        warn!("session server unavailable or rate limiting, serving cached profile of {uuid}");
+        return Ok(Profile::cached(entry));
+    }
+
+    let resp = upstream?;
+    let status = resp.status();
+    let body = resp.text().await?;
+    if status == StatusCode::OK
+        && let Ok(profile) = serde_json::from_str(&body)
+    {
+        cache.insert(uuid, profile, now).await;
+    }
+    Ok(Profile { status, body, cached: false })
+}
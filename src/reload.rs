@@ -0,0 +1,115 @@
+//! Reloads the file account backend when the file changes on disk or the
+//! process receives SIGHUP, and the local blocklist when its file changes.
+
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::{path::Path, sync::Arc, time::Duration};
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+use crate::{AccountMap, blocklist::Blocklist};
+
+// Editors and our own atomic writes produce bursts of events per save.
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+pub fn spawn(accounts: AccountMap, watch: bool) {
+    let Some(file) = accounts.file_accounts() else {
+        return;
+    };
+    let (tx, mut rx) = mpsc::unbounded_channel::<&'static str>();
+
+    let watcher = if watch {
+        match watch_file(file.path(), tx.clone()) {
+            Ok(watcher) => {
+                info!("watching {} for changes", file.path().display());
+                Some(watcher)
+            }
+            Err(e) => {
+                warn!("failed to watch {}: {e}", file.path().display());
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{SignalKind, signal};
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                warn!("failed to install SIGHUP handler: {e}");
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            if tx.send("SIGHUP").is_err() {
+                return;
+            }
+        }
+    });
+    #[cfg(not(unix))]
+    drop(tx);
+
+    tokio::spawn(async move {
+        // The watcher stops when dropped.
+        let _watcher = watcher;
+        while let Some(reason) = rx.recv().await {
+            tokio::time::sleep(DEBOUNCE).await;
+            while rx.try_recv().is_ok() {}
+
+            let Some(file) = accounts.file_accounts() else {
+                return;
+            };
+            info!("reloading {} ({reason})", file.path().display());
+            if let Err(e) = file.reload().await {
+                error!("keeping current accounts, reload failed: {e}");
+            }
+        }
+    });
+}
+
+/// Picks up changes made to the blocklist file outside the admin API, such as
+/// by the `block` and `unblock` subcommands.
+pub fn spawn_blocklist(blocklist: Arc<Blocklist>) {
+    let (tx, mut rx) = mpsc::unbounded_channel::<&'static str>();
+    let watcher = match watch_file(blocklist.path(), tx) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            warn!("failed to watch {}: {e}", blocklist.path().display());
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        let _watcher = watcher;
+        while rx.recv().await.is_some() {
+            tokio::time::sleep(DEBOUNCE).await;
+            while rx.try_recv().is_ok() {}
+            if let Err(e) = blocklist.reload().await {
+                error!("keeping current blocklist, reload failed: {e}");
+            }
+        }
+    });
+}
+
+fn watch_file(
+    path: &Path,
+    tx: mpsc::UnboundedSender<&'static str>,
+) -> notify::Result<notify::RecommendedWatcher> {
+    // Watch the directory: atomic writes replace the file, which would end a
+    // watch on the file itself.
+    let dir = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let name = path.file_name().map(|n| n.to_os_string());
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let Ok(event) = event else {
+            return;
+        };
+        let relevant = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+            && event.paths.iter().any(|p| p.file_name() == name.as_deref());
+        if relevant {
+            let _ = tx.send("file changed");
+        }
+    })?;
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    Ok(watcher)
+}
@@ -0,0 +1,294 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    Row,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions},
+};
+use std::{collections::HashMap, io, path::PathBuf};
+
+use crate::persist;
+use tokio::sync::Mutex;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StoreError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("sql error: {0}")]
+    Sql(#[from] sqlx::Error),
+}
+
+/// A server join recorded for a self-hosted account.
+#[derive(Debug, Clone)]
+pub struct JoinedSession {
+    pub uuid: String,
+    pub timestamp: u64,
+}
+
+/// Storage for pending joins, looked up again by `hasJoined`.
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    /// Records that `username` (profile `uuid`) joined `server_id` at `timestamp`.
+    async fn insert(&self, username: &str, uuid: &str, server_id: &str, timestamp: u64) -> Result<(), StoreError>;
+
+    /// Looks up the join of `username` to `server_id`, if any.
+    async fn lookup(&self, username: &str, server_id: &str) -> Result<Option<JoinedSession>, StoreError>;
+
+    /// Like `lookup`, but removes the entry so it can only be used once.
+    async fn take(&self, username: &str, server_id: &str) -> Result<Option<JoinedSession>, StoreError>;
+
+    /// Drops every server entry that joined before `cutoff`, along with users
+    /// left without any. Returns the number of server entries removed.
+    async fn expire(&self, cutoff: u64) -> Result<usize, StoreError>;
+
+    /// Flushes pending changes to durable storage.
+    async fn persist(&self) -> Result<(), StoreError>;
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Session {
+    uuid: String,
+    servers: HashMap<String, u64>
+}
+
+/// The original `sessions.json` format: username -> uuid and joined servers.
+pub struct JsonSessionStore {
+    path: PathBuf,
+    sessions: Mutex<HashMap<String, Session>>,
+    // Serializes file writes without holding the session lock during IO.
+    write_lock: Mutex<()>,
+}
+
+impl JsonSessionStore {
+    pub fn open(path: PathBuf) -> Self {
+        let sessions = persist::read_json_or_recover(&path);
+        JsonSessionStore { path, sessions: Mutex::new(sessions), write_lock: Mutex::new(()) }
+    }
+}
+
+#[async_trait]
+impl SessionStore for JsonSessionStore {
+    async fn insert(&self, username: &str, uuid: &str, server_id: &str, timestamp: u64) -> Result<(), StoreError> {
+        let mut sessions = self.sessions.lock().await;
+        let session = sessions
+            .entry(username.to_string())
+            .or_insert_with(|| Session { uuid: uuid.to_string(), servers: HashMap::new() });
+        // Latest join wins, as in the SQLite store.
+        session.uuid = uuid.to_string();
+        session.servers.insert(server_id.to_string(), timestamp);
+        Ok(())
+    }
+
+    async fn lookup(&self, username: &str, server_id: &str) -> Result<Option<JoinedSession>, StoreError> {
+        let sessions = self.sessions.lock().await;
+        Ok(sessions.get(username).and_then(|session| {
+            session.servers.get(server_id).map(|&timestamp| JoinedSession {
+                uuid: session.uuid.clone(),
+                timestamp,
+            })
+        }))
+    }
+
+    async fn take(&self, username: &str, server_id: &str) -> Result<Option<JoinedSession>, StoreError> {
+        let mut sessions = self.sessions.lock().await;
+        let Some(session) = sessions.get_mut(username) else {
+            return Ok(None);
+        };
+        let joined = session.servers.remove(server_id).map(|timestamp| JoinedSession {
+            uuid: session.uuid.clone(),
+            timestamp,
+        });
+        if session.servers.is_empty() {
+            sessions.remove(username);
+        }
+        Ok(joined)
+    }
+
+    async fn expire(&self, cutoff: u64) -> Result<usize, StoreError> {
+        let mut sessions = self.sessions.lock().await;
+        let mut removed = 0;
+        sessions.retain(|_, session| {
+            let before = session.servers.len();
+            session.servers.retain(|_, &mut t| t >= cutoff);
+            removed += before - session.servers.len();
+            !session.servers.is_empty()
+        });
+        Ok(removed)
+    }
+
+    async fn persist(&self) -> Result<(), StoreError> {
+        let _write = self.write_lock.lock().await;
+        let contents = serde_json::to_vec(&*self.sessions.lock().await)?;
+        persist::write_atomic_async(self.path.clone(), contents).await?;
+        Ok(())
+    }
+}
+
+/// Embedded SQLite store, every join is written as its own row.
+pub struct SqliteSessionStore {
+    pool: SqlitePool,
+}
+
+impl SqliteSessionStore {
+    pub async fn open(path: PathBuf) -> Result<Self, StoreError> {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal);
+        let pool = SqlitePoolOptions::new().connect_with(options).await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS sessions (
+                username TEXT NOT NULL,
+                server_id TEXT NOT NULL,
+                uuid TEXT NOT NULL,
+                joined_at INTEGER NOT NULL,
+                PRIMARY KEY (username, server_id)
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        Ok(SqliteSessionStore { pool })
+    }
+}
+
+#[async_trait]
+impl SessionStore for SqliteSessionStore {
+    async fn insert(&self, username: &str, uuid: &str, server_id: &str, timestamp: u64) -> Result<(), StoreError> {
+        sqlx::query(
+            "INSERT INTO sessions (username, server_id, uuid, joined_at) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (username, server_id) DO UPDATE SET uuid = excluded.uuid, joined_at = excluded.joined_at",
+        )
+        .bind(username)
+        .bind(server_id)
+        .bind(uuid)
+        .bind(timestamp as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn lookup(&self, username: &str, server_id: &str) -> Result<Option<JoinedSession>, StoreError> {
+        let row = sqlx::query("SELECT uuid, joined_at FROM sessions WHERE username = ?1 AND server_id = ?2")
+            .bind(username)
+            .bind(server_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|row| JoinedSession {
+            uuid: row.get("uuid"),
+            timestamp: row.get::<i64, _>("joined_at") as u64,
+        }))
+    }
+
+    async fn take(&self, username: &str, server_id: &str) -> Result<Option<JoinedSession>, StoreError> {
+        let row = sqlx::query(
+            "DELETE FROM sessions WHERE username = ?1 AND server_id = ?2 RETURNING uuid, joined_at",
+        )
+        .bind(username)
+        .bind(server_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(|row| JoinedSession {
+            uuid: row.get("uuid"),
+            timestamp: row.get::<i64, _>("joined_at") as u64,
+        }))
+    }
+
+    async fn expire(&self, cutoff: u64) -> Result<usize, StoreError> {
+        let result = sqlx::query("DELETE FROM sessions WHERE joined_at < ?1")
+            .bind(cutoff as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() as usize)
+    }
+
+    async fn persist(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persist::tests::TempPath;
+
+    async fn take_removes_the_join(store: &dyn SessionStore) {
+        store.insert("steve", "uuid-1", "server-a", 100).await.unwrap();
+        store.insert("steve", "uuid-1", "server-b", 100).await.unwrap();
+
+        let joined = store.take("steve", "server-a").await.unwrap().unwrap();
+        assert_eq!(joined.uuid, "uuid-1");
+        assert_eq!(joined.timestamp, 100);
+        assert!(store.take("steve", "server-a").await.unwrap().is_none());
+        assert!(store.lookup("steve", "server-a").await.unwrap().is_none());
+        // Other servers of the same user are untouched.
+        assert!(store.lookup("steve", "server-b").await.unwrap().is_some());
+        assert!(store.take("alex", "server-a").await.unwrap().is_none());
+    }
+
+    async fn expire_drops_old_joins(store: &dyn SessionStore) {
+        store.insert("steve", "uuid-1", "server-a", 100).await.unwrap();
+        store.insert("steve", "uuid-1", "server-b", 200).await.unwrap();
+        store.insert("alex", "uuid-2", "server-a", 50).await.unwrap();
+
+        assert_eq!(store.expire(150).await.unwrap(), 2);
+        assert!(store.lookup("steve", "server-a").await.unwrap().is_none());
+        assert!(store.lookup("alex", "server-a").await.unwrap().is_none());
+        assert_eq!(store.lookup("steve", "server-b").await.unwrap().unwrap().timestamp, 200);
+        assert_eq!(store.expire(150).await.unwrap(), 0);
+    }
+
+    async fn insert_overwrites_uuid(store: &dyn SessionStore) {
+        store.insert("steve", "uuid-1", "server-a", 100).await.unwrap();
+        store.insert("steve", "uuid-2", "server-a", 200).await.unwrap();
+        let joined = store.lookup("steve", "server-a").await.unwrap().unwrap();
+        assert_eq!(joined.uuid, "uuid-2");
+        assert_eq!(joined.timestamp, 200);
+    }
+
+    #[tokio::test]
+    async fn json_take() {
+        let path = TempPath::new("json-take.json");
+        take_removes_the_join(&JsonSessionStore::open(path.0.clone())).await;
+    }
+
+    #[tokio::test]
+    async fn json_expire() {
+        let path = TempPath::new("json-expire.json");
+        expire_drops_old_joins(&JsonSessionStore::open(path.0.clone())).await;
+    }
+
+    #[tokio::test]
+    async fn json_insert_overwrites_uuid() {
+        let path = TempPath::new("json-insert.json");
+        insert_overwrites_uuid(&JsonSessionStore::open(path.0.clone())).await;
+    }
+
+    #[tokio::test]
+    async fn json_persists() {
+        let path = TempPath::new("json-persist.json");
+        let store = JsonSessionStore::open(path.0.clone());
+        store.insert("steve", "uuid-1", "server-a", 100).await.unwrap();
+        store.persist().await.unwrap();
+        let reopened = JsonSessionStore::open(path.0.clone());
+        assert_eq!(reopened.lookup("steve", "server-a").await.unwrap().unwrap().uuid, "uuid-1");
+    }
+
+    #[tokio::test]
+    async fn sqlite_take() {
+        let path = TempPath::new("sqlite-take.db");
+        take_removes_the_join(&SqliteSessionStore::open(path.0.clone()).await.unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_expire() {
+        let path = TempPath::new("sqlite-expire.db");
+        expire_drops_old_joins(&SqliteSessionStore::open(path.0.clone()).await.unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_insert_overwrites_uuid() {
+        let path = TempPath::new("sqlite-insert.db");
+        insert_overwrites_uuid(&SqliteSessionStore::open(path.0.clone()).await.unwrap()).await;
+    }
+}
@@ -0,0 +1,213 @@
+//! Our own Yggdrasil signing key, and `textures` properties signed with it
+//! for self-hosted profiles. Servers verify them against the public key,
+//! published alongside Mojang's at `/publickeys` (for servers that resolve
+//! api.minecraftservices.com to us) and for authlib-injector, which is given
+//! this service as its API root: metadata at `/`, keys at
+//! `/minecraftservices/publickeys`.
+
+use axum::{Json, Router, extract::State, http::StatusCode, routing::get};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use rsa::{
+    RsaPrivateKey,
+    pkcs1v15::SigningKey,
+    pkcs8::{DecodePrivateKey, EncodePrivateKey, EncodePublicKey, LineEnding},
+    rand_core::OsRng,
+    signature::{SignatureEncoding, Signer as _},
+};
+use serde_json::{Value, json};
+use sha1::Sha1;
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+use tracing::info;
+
+use crate::{
+    AppState,
+    error::HandlerError,
+    upstream::{Cached, Upstream},
+};
+
+/// Size of generated keys, the same as Mojang's.
+const KEY_BITS: usize = 4096;
+
+/// How long Mojang's public keys are reused before being fetched again.
+const UPSTREAM_KEYS_TTL: Duration = Duration::from_secs(3600);
+
+/// SHA1withRSA signer for profile properties.
+pub struct Signer {
+    key: SigningKey<Sha1>,
+    /// SubjectPublicKeyInfo, as DER and as PEM.
+    public_key: Vec<u8>,
+    public_key_pem: String,
+    /// `/publickeys` of the services API.
+    upstream_keys: Cached<Value>,
+}
+
+impl Signer {
+    /// Loads the PKCS#8 PEM private key at `path`, generating and saving a
+    /// new one if there is none yet.
+    pub fn load_or_generate(path: &Path) -> Result<Self, String> {
+        let key = if path.exists() {
+            let pem = fs::read_to_string(path)
+                .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+            RsaPrivateKey::from_pkcs8_pem(&pem)
+                .map_err(|e| format!("failed to parse {}: {e}", path.display()))?
+        } else {
+            info!("generating a {KEY_BITS} bit signing key, this can take a while");
+            let key = RsaPrivateKey::new(&mut OsRng, KEY_BITS)
+                .map_err(|e| format!("failed to generate key: {e}"))?;
+            let pem = key
+                .to_pkcs8_pem(LineEnding::LF)
+                .map_err(|e| format!("failed to encode key: {e}"))?;
+            write_private(path, pem.as_bytes())
+                .map_err(|e| format!("failed to write {}: {e}", path.display()))?;
+            info!("wrote signing key to {}", path.display());
+            key
+        };
+        let public = key.to_public_key();
+        let encode_err = |e| format!("failed to encode public key: {e}");
+        let public_key = public.to_public_key_der().map_err(encode_err)?.into_vec();
+        let public_key_pem = public.to_public_key_pem(LineEnding::LF).map_err(encode_err)?;
+        Ok(Signer {
+            key: SigningKey::new(key),
+            public_key,
+            public_key_pem,
+            upstream_keys: Cached::new("public keys", UPSTREAM_KEYS_TTL),
+        })
+    }
+
+    /// Base64 SHA1withRSA signature of `data`, as sent in property signatures.
+    pub fn sign(&self, data: &str) -> String {
+        STANDARD.encode(self.key.sign(data.as_bytes()).to_bytes())
+    }
+
+    /// `sign` on the blocking pool. A signature with a 4096 bit key takes
+    /// milliseconds of CPU, too long to hold up a worker thread.
+    pub async fn sign_blocking(self: Arc<Self>, data: String) -> String {
+        tokio::task::spawn_blocking(move || self.sign(&data))
+            .await
+            .expect("signing doesn't panic")
+    }
+
+    /// The public key as base64 DER, the form `/publickeys` lists.
+    pub fn public_key_base64(&self) -> String {
+        STANDARD.encode(&self.public_key)
+    }
+
+    /// The public key as PEM, the form authlib-injector expects.
+    pub fn public_key_pem(&self) -> &str {
+        &self.public_key_pem
+    }
+
+    /// Mojang's published keys, refetched once older than
+    /// `UPSTREAM_KEYS_TTL`. The last ones fetched are kept while the services
+    /// API can't be reached; `None` if it never could.
+    async fn upstream_keys(&self, upstream: &Upstream) -> Option<Value> {
+        let fetch = || async {
+            let resp = upstream.public_keys().await.map_err(|e| e.to_string())?;
+            if resp.status() != StatusCode::OK {
+                return Err(format!("answered {}", resp.status()));
+            }
+            resp.json::<Value>().await.map_err(|e| e.to_string())
+        };
+        self.upstream_keys.get(fetch).await
+    }
+}
+
+/// Creates `path` readable only by us, failing if it already exists.
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+/// Skin and cape of a self-hosted profile.
+pub struct Textures<'a> {
+    pub skin: Option<&'a str>,
+    /// Whether the skin uses the slim (Alex) arm model.
+    pub slim: bool,
+    pub cape: Option<&'a str>,
+}
+
+impl Textures<'_> {
+    /// The base64 value of the `textures` property for `uuid` and `name`.
+    pub fn value(&self, uuid: &str, name: &str, timestamp_ms: u64, signed: bool) -> String {
+        let mut textures = serde_json::Map::new();
+        if let Some(url) = self.skin {
+            let mut skin = json!({ "url": url });
+            if self.slim {
+                skin["metadata"] = json!({ "model": "slim" });
+            }
+            textures.insert("SKIN".to_string(), skin);
+        }
+        if let Some(url) = self.cape {
+            textures.insert("CAPE".to_string(), json!({ "url": url }));
+        }
+        let payload = json!({
+            "timestamp": timestamp_ms,
+            "profileId": uuid,
+            "profileName": name,
+            "signatureRequired": signed,
+            "textures": textures,
+        });
+        STANDARD.encode(payload.to_string())
+    }
+}
+
+/// A `textures` property with `value`, and `signature` if it is signed.
+pub fn property(value: String, signature: Option<String>) -> Value {
+    let mut property = json!({ "name": "textures", "value": value });
+    if let Some(signature) = signature {
+        property["signature"] = Value::String(signature);
+    }
+    property
+}
+
+/// Routes publishing the public key, mounted when a signing key is set.
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/", get(metadata))
+        .route("/publickeys", get(public_keys))
+        .route("/minecraftservices/publickeys", get(public_keys))
+}
+
+fn signer(state: &AppState) -> &Signer {
+    state.signer.as_deref().expect("routes are only mounted with a signer")
+}
+
+/// authlib-injector API metadata.
+async fn metadata(State(state): State<AppState>) -> Json<Value> {
+    Json(json!({
+        "meta": {
+            "implementationName": env!("CARGO_PKG_NAME"),
+            "implementationVersion": env!("CARGO_PKG_VERSION"),
+        },
+        "skinDomains": state.profiles.texture_domains(),
+        "signaturePublickey": signer(&state).public_key_pem(),
+    }))
+}
+
+/// Mojang's `/publickeys` with our key added to `profilePropertyKeys`, so a
+/// server trusting the list accepts both our textures and Mojang's. The
+/// certificate keys stay Mojang's alone, we don't issue player certificates.
+/// Until Mojang's keys could be fetched this fails rather than list ours
+/// alone, which would make servers reject every Mojang-signed profile.
+async fn public_keys(State(state): State<AppState>) -> Result<Json<Value>, HandlerError> {
+    let signer = signer(&state);
+    let upstream = signer.upstream_keys(&state.upstream).await.ok_or(HandlerError::NoPublicKeys)?;
+    let mut property_keys = upstream["profilePropertyKeys"].as_array().cloned().unwrap_or_default();
+    property_keys.push(json!({ "publicKey": signer.public_key_base64() }));
+    let certificate_keys = upstream["playerCertificateKeys"].as_array().cloned().unwrap_or_default();
+    Ok(Json(json!({ "profilePropertyKeys": property_keys, "playerCertificateKeys": certificate_keys })))
+}
@@ -0,0 +1,116 @@
+//! Salted, optionally peppered token hashes for the file account backend,
+//! and request signatures for the API backend.
+//!
+//! A hashed key in `accounts.json` looks like `sha256$<salt>$<digest>`, where
+//! the digest is HMAC-SHA256 keyed with the pepper over `salt || token`. Keys
+//! without the prefix are plaintext tokens.
+
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::Sha256;
+
+const PREFIX: &str = "sha256$";
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub fn is_hashed(key: &str) -> bool {
+    key.starts_with(PREFIX)
+}
+
+fn mac(pepper: &str, salt: &[u8], token: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(pepper.as_bytes()).expect("hmac accepts any key length");
+    mac.update(salt);
+    mac.update(token.as_bytes());
+    mac
+}
+
+pub fn hash(token: &str, pepper: &str) -> String {
+    let salt = rand::rng().random::<[u8; 16]>();
+    let digest = mac(pepper, &salt, token).finalize().into_bytes();
+    format!("{PREFIX}{}${}", hex::encode(salt), hex::encode(digest))
+}
+
+/// Checks `token` against a stored key, in constant time for hashed keys.
+pub fn verify(key: &str, token: &str, pepper: &str) -> bool {
+    let Some(rest) = key.strip_prefix(PREFIX) else {
+        return !is_hashed(token) && constant_time_eq(key.as_bytes(), token.as_bytes());
+    };
+    let Some((salt, digest)) = rest.split_once('$') else {
+        return false;
+    };
+    let (Ok(salt), Ok(digest)) = (hex::decode(salt), hex::decode(digest)) else {
+        return false;
+    };
+    mac(pepper, &salt, token).verify_slice(&digest).is_ok()
+}
+
+/// Signature for an API backend lookup: hex HMAC-SHA256 keyed with the
+/// shared secret over `"{timestamp}\n{token}"`.
+pub fn sign(secret: &str, timestamp: &str, token: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("hmac accepts any key length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b"\n");
+    mac.update(token.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub fn generate() -> String {
+    hex::encode(rand::rng().random::<[u8; 32]>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_verifies_its_token() {
+        let key = hash("secret", "pepper");
+        assert!(is_hashed(&key));
+        assert!(verify(&key, "secret", "pepper"));
+        assert!(!verify(&key, "other", "pepper"));
+    }
+
+    #[test]
+    fn hash_is_salted() {
+        assert_ne!(hash("secret", "pepper"), hash("secret", "pepper"));
+    }
+
+    #[test]
+    fn pepper_mismatch_fails() {
+        let key = hash("secret", "pepper");
+        assert!(!verify(&key, "secret", "other"));
+        assert!(!verify(&key, "secret", ""));
+    }
+
+    #[test]
+    fn plaintext_keys_compare_directly() {
+        assert!(verify("secret", "secret", "pepper"));
+        assert!(!verify("secret", "Secret", "pepper"));
+        // A stored hash sent back as the token must not match itself.
+        let key = hash("secret", "pepper");
+        assert!(!verify(&key, &key, "pepper"));
+    }
+
+    #[test]
+    fn malformed_hashes_fail() {
+        assert!(!verify("sha256$nodigest", "secret", "pepper"));
+        assert!(!verify("sha256$zz$zz", "secret", "pepper"));
+    }
+
+    #[test]
+    fn sign_matches_known_answer() {
+        // hmac.new(b"shared-secret", b"1700000000\ntoken-1", hashlib.sha256).hexdigest()
+        assert_eq!(
+            sign("shared-secret", "1700000000", "token-1"),
+            "80d0d2fce70cd7e829b8d123e7c579b631205cc5f8b11efdd57d319b42df94df"
+        );
+        assert_eq!(
+            sign("shared-secret", "1700000001", "token-1"),
+            "a3c8b8294a9a5ddae2817f9a02f5a5b35f8592ef608e6cc76034dd00f9a1d2bc"
+        );
+    }
+}
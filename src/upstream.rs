@@ -0,0 +1,445 @@
+use reqwest::{
+    Client, RequestBuilder, Response, Url,
+    dns::{Addrs, Name, Resolve, Resolving},
+    header::HOST,
+};
+use serde::Serialize;
+use std::{
+    future::Future,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::{Mutex, RwLock};
+use tracing::{info, warn};
+use trust_dns_resolver::{
+    TokioAsyncResolver,
+    config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts},
+};
+
+use crate::config::{BreakerConfig, UpstreamConfig, UpstreamDns};
+
+/// The upstream session server, Mojang's unless configured otherwise, shared
+/// by all handlers so connections and DNS answers are reused across requests.
+pub struct Upstream {
+    client: Client,
+    base: String,
+    services: String,
+    host: Option<String>,
+    attempts: u32,
+    backoff: Duration,
+    breaker: Breaker,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum UpstreamError {
+    #[error(transparent)]
+    Request(#[from] reqwest::Error),
+    #[error("circuit open after repeated session server failures")]
+    CircuitOpen,
+}
+
+/// Counts consecutive upstream failures. Past the threshold the circuit
+/// opens and calls fail without being made; once the cooldown is over a
+/// single call is let through, and closes it again if it succeeds.
+struct Breaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Default)]
+struct BreakerState {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+/// A value fetched from upstream and reused for `ttl`. A failed fetch keeps
+/// the last good value and is only retried after `ttl`, or a minute if that
+/// is shorter. One caller fetches at a time; the others get the last good
+/// value meanwhile, or wait for the fetch if there is none yet.
+pub struct Cached<T> {
+    what: &'static str,
+    ttl: Duration,
+    state: RwLock<CachedState<T>>,
+    fetching: Mutex<()>,
+}
+
+struct CachedState<T> {
+    value: Option<T>,
+    /// When the next fetch is due, `None` before the first one.
+    refresh_at: Option<Instant>,
+}
+
+const RETRY_FAILED_FETCH: Duration = Duration::from_secs(60);
+
+/// Resolves upstream hostnames through our own DNS servers instead of the
+/// system resolver, which points the session server at us. Plugged into
+/// reqwest so TLS still verifies the certificate against the real hostname.
+struct UpstreamResolver(TokioAsyncResolver);
+
+impl Resolve for UpstreamResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let resolver = self.0.clone();
+        Box::pin(async move {
+            // Fully qualified, so search domains don't apply.
+            let name = format!("{}.", name.as_str().trim_end_matches('.'));
+            let lookup = resolver.lookup_ip(name).await?;
+            let addrs: Addrs = Box::new(
+                lookup
+                    .iter()
+                    .map(|ip| SocketAddr::new(ip, 0))
+                    .collect::<Vec<_>>()
+                    .into_iter(),
+            );
+            Ok(addrs)
+        })
+    }
+}
+
+impl Upstream {
+    /// Builds the client for a validated config.
+    pub fn new(config: &UpstreamConfig) -> Self {
+        let mut builder = Client::builder()
+            .connect_timeout(Duration::from_millis(config.connect_timeout_ms))
+            .read_timeout(Duration::from_millis(config.read_timeout_ms));
+        if let Some(resolver) = resolver_config(config) {
+            let resolver = TokioAsyncResolver::tokio(resolver, ResolverOpts::default());
+            builder = builder.dns_resolver(Arc::new(UpstreamResolver(resolver)));
+        }
+        if let Some(address) = config.address {
+            let url = Url::parse(&config.url).expect("upstream.url is validated");
+            // The port is ignored, the URL's own is used.
+            builder = builder.resolve(url.host_str().unwrap(), SocketAddr::new(address, 0));
+        }
+        Upstream {
+            client: builder.build().unwrap(),
+            base: config.url.trim_end_matches('/').to_string(),
+            services: config.services_url.trim_end_matches('/').to_string(),
+            host: config.host.clone(),
+            attempts: config.retry.attempts,
+            backoff: Duration::from_millis(config.retry.backoff_ms),
+            breaker: Breaker::new(&config.breaker),
+        }
+    }
+
+    fn request(&self, builder: RequestBuilder) -> RequestBuilder {
+        match &self.host {
+            Some(host) => builder.header(HOST, host),
+            None => builder,
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/session/minecraft/{path}", self.base)
+    }
+
+    /// GET `/session/minecraft/{path}`, retried with backoff on network
+    /// errors and 5xx answers.
+    pub async fn get(&self, path: &str, query: &[(&str, &str)]) -> Result<Response, UpstreamError> {
+        self.get_url(self.url(path), query).await
+    }
+
+    /// GET `/blockedservers`. Neither retried nor counted by the breaker:
+    /// the answer is `Cached`, so a failure only means serving the last one.
+    pub async fn blocked_servers(&self) -> Result<Response, reqwest::Error> {
+        self.request(self.client.get(format!("{}/blockedservers", self.base))).send().await
+    }
+
+    /// GET the services API's `/publickeys`. Neither retried nor counted by
+    /// the breaker, which tracks the session server only.
+    pub async fn public_keys(&self) -> Result<Response, reqwest::Error> {
+        self.client.get(format!("{}/publickeys", self.services)).send().await
+    }
+
+    async fn get_url(&self, url: String, query: &[(&str, &str)]) -> Result<Response, UpstreamError> {
+        let request = self.request(self.client.get(&url).query(query));
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+        loop {
+            let result = self.send(request.try_clone().expect("GET has no streaming body")).await;
+            let failure = match &result {
+                Ok(resp) if resp.status().is_server_error() => resp.status().to_string(),
+                Err(UpstreamError::Request(e)) if e.is_timeout() => "timed out".to_string(),
+                Err(UpstreamError::Request(e)) => e.to_string(),
+                _ => return result,
+            };
+            if attempt >= self.attempts {
+                return result;
+            }
+            warn!("GET {url} failed ({failure}), retry {attempt} in {backoff:?}");
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+
+    /// POST `/session/minecraft/{path}` with a JSON body. Not retried.
+    pub async fn post<T: Serialize>(&self, path: &str, body: &T) -> Result<Response, UpstreamError> {
+        self.send(self.request(self.client.post(self.url(path)).json(body))).await
+    }
+
+    async fn send(&self, request: RequestBuilder) -> Result<Response, UpstreamError> {
+        if !self.breaker.allow().await {
+            return Err(UpstreamError::CircuitOpen);
+        }
+        let result = request.send().await;
+        match &result {
+            Ok(resp) if !resp.status().is_server_error() => self.breaker.success().await,
+            _ => self.breaker.failure().await,
+        }
+        Ok(result?)
+    }
+}
+
+impl<T: Clone> Cached<T> {
+    /// `what` names the value in the log when fetching it fails.
+    pub fn new(what: &'static str, ttl: Duration) -> Self {
+        Cached {
+            what,
+            ttl,
+            state: RwLock::new(CachedState { value: None, refresh_at: None }),
+            fetching: Mutex::new(()),
+        }
+    }
+
+    /// The cached value, calling `fetch` first if it is due. `None` until a
+    /// fetch has succeeded.
+    pub async fn get<F, Fut>(&self, fetch: F) -> Option<T>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, String>>,
+    {
+        if let Some(value) = self.fresh().await {
+            return value;
+        }
+        let _fetching = match self.fetching.try_lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                let last = self.state.read().await.value.clone();
+                match last {
+                    Some(value) => return Some(value),
+                    None => self.fetching.lock().await,
+                }
+            }
+        };
+        // Whoever held the lock may have just fetched it.
+        if let Some(value) = self.fresh().await {
+            return value;
+        }
+
+        let fetched = fetch().await;
+        let mut state = self.state.write().await;
+        match fetched {
+            Ok(value) => {
+                state.value = Some(value);
+                state.refresh_at = Some(Instant::now() + self.ttl);
+            }
+            Err(e) => {
+                let retry = self.ttl.min(RETRY_FAILED_FETCH);
+                warn!("failed to fetch {} from upstream, retrying in {retry:?}: {e}", self.what);
+                state.refresh_at = Some(Instant::now() + retry);
+            }
+        }
+        state.value.clone()
+    }
+
+    /// `Some` with the cached value, if any, unless a fetch is due.
+    async fn fresh(&self) -> Option<Option<T>> {
+        let state = self.state.read().await;
+        match state.refresh_at {
+            Some(at) if Instant::now() < at => Some(state.value.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl Breaker {
+    fn new(config: &BreakerConfig) -> Self {
+        Breaker {
+            threshold: config.threshold,
+            cooldown: Duration::from_secs(config.cooldown),
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    async fn allow(&self) -> bool {
+        let mut state = self.state.lock().await;
+        match state.open_until {
+            Some(until) if Instant::now() < until => false,
+            Some(_) => {
+                // Half open: this call probes, the rest keep failing fast.
+                state.open_until = Some(Instant::now() + self.cooldown);
+                true
+            }
+            None => true,
+        }
+    }
+
+    async fn success(&self) {
+        let mut state = self.state.lock().await;
+        if state.open_until.take().is_some() {
+            info!("session server recovered, closing circuit");
+        }
+        state.failures = 0;
+    }
+
+    async fn failure(&self) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut state = self.state.lock().await;
+        state.failures += 1;
+        if state.failures >= self.threshold {
+            if state.open_until.is_none() {
+                warn!(
+                    "session server failed {} times in a row, opening circuit for {:?}",
+                    state.failures, self.cooldown
+                );
+            }
+            state.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+}
+
+/// DNS servers for the upstream hostname, or `None` for the system resolver.
+fn resolver_config(config: &UpstreamConfig) -> Option<ResolverConfig> {
+    let servers = config.dns_servers().expect("upstream.dns_servers is validated");
+    if !servers.is_empty() {
+        let mut resolver = ResolverConfig::new();
+        for server in servers {
+            resolver.add_name_server(NameServerConfig::new(server, Protocol::Udp));
+            resolver.add_name_server(NameServerConfig::new(server, Protocol::Tcp));
+        }
+        return Some(resolver);
+    }
+    match config.dns {
+        UpstreamDns::Cloudflare => Some(ResolverConfig::cloudflare()),
+        UpstreamDns::Google => Some(ResolverConfig::google()),
+        UpstreamDns::Quad9 => Some(ResolverConfig::quad9()),
+        UpstreamDns::System => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const COOLDOWN: Duration = Duration::from_millis(50);
+
+    fn breaker(threshold: u32) -> Breaker {
+        Breaker { threshold, cooldown: COOLDOWN, state: Mutex::new(BreakerState::default()) }
+    }
+
+    #[tokio::test]
+    async fn opens_after_threshold_failures() {
+        let breaker = breaker(3);
+        breaker.failure().await;
+        breaker.failure().await;
+        assert!(breaker.allow().await);
+        breaker.failure().await;
+        assert!(!breaker.allow().await);
+    }
+
+    #[tokio::test]
+    async fn success_resets_the_count() {
+        let breaker = breaker(2);
+        breaker.failure().await;
+        breaker.success().await;
+        breaker.failure().await;
+        assert!(breaker.allow().await);
+    }
+
+    #[tokio::test]
+    async fn half_open_lets_one_probe_through() {
+        let breaker = breaker(1);
+        breaker.failure().await;
+        assert!(!breaker.allow().await);
+        tokio::time::sleep(COOLDOWN).await;
+        assert!(breaker.allow().await);
+        // Everyone else keeps failing fast while the probe is out.
+        assert!(!breaker.allow().await);
+    }
+
+    #[tokio::test]
+    async fn successful_probe_closes() {
+        let breaker = breaker(1);
+        breaker.failure().await;
+        tokio::time::sleep(COOLDOWN).await;
+        assert!(breaker.allow().await);
+        breaker.success().await;
+        assert!(breaker.allow().await);
+        assert!(breaker.allow().await);
+    }
+
+    #[tokio::test]
+    async fn failed_probe_reopens() {
+        let breaker = breaker(1);
+        breaker.failure().await;
+        tokio::time::sleep(COOLDOWN).await;
+        assert!(breaker.allow().await);
+        breaker.failure().await;
+        assert!(!breaker.allow().await);
+    }
+
+    async fn count(fetches: &AtomicUsize, result: Result<u32, &str>) -> Result<u32, String> {
+        fetches.fetch_add(1, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        result.map_err(String::from)
+    }
+
+    #[tokio::test]
+    async fn cached_value_is_reused_within_ttl() {
+        let cached = Cached::new("test", Duration::from_secs(60));
+        let fetches = AtomicUsize::new(0);
+        assert_eq!(cached.get(|| count(&fetches, Ok(1))).await, Some(1));
+        assert_eq!(cached.get(|| count(&fetches, Ok(2))).await, Some(1));
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_fetch_keeps_last_value_and_is_not_retried() {
+        let cached = Cached::new("test", Duration::from_millis(50));
+        let fetches = AtomicUsize::new(0);
+        assert_eq!(cached.get(|| count(&fetches, Ok(1))).await, Some(1));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(cached.get(|| count(&fetches, Err("down"))).await, Some(1));
+        assert_eq!(cached.get(|| count(&fetches, Err("down"))).await, Some(1));
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(cached.get(|| count(&fetches, Ok(2))).await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn first_failure_is_not_retried_either() {
+        let cached = Cached::<u32>::new("test", Duration::from_secs(60));
+        let fetches = AtomicUsize::new(0);
+        assert_eq!(cached.get(|| count(&fetches, Err("down"))).await, None);
+        assert_eq!(cached.get(|| count(&fetches, Ok(1))).await, None);
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn concurrent_callers_fetch_once() {
+        let cached = Cached::new("test", Duration::from_millis(50));
+        let fetches = AtomicUsize::new(0);
+        // Nothing cached yet: the others wait for the first fetch.
+        let (a, b) = tokio::join!(cached.get(|| count(&fetches, Ok(1))), cached.get(|| count(&fetches, Ok(2))));
+        assert_eq!((a, b), (Some(1), Some(1)));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // Due again: the others get the last value while one refetches.
+        let (a, b) = tokio::join!(cached.get(|| count(&fetches, Ok(3))), cached.get(|| count(&fetches, Ok(4))));
+        assert_eq!((a, b), (Some(3), Some(1)));
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn zero_threshold_never_opens() {
+        let breaker = breaker(0);
+        for _ in 0..10 {
+            breaker.failure().await;
+        }
+        assert!(breaker.allow().await);
+    }
+}